@@ -199,9 +199,12 @@ macro_rules! module_plugin_trait_define{
 
 /// Implements the necessary traits for all configuration related types of a
 /// `FederationServer` module.
+///
+/// Modules whose consensus config encoding changed between consensus versions
+/// can pass `from_erased = <fn>` to decode configs of older versions.
 #[macro_export]
 macro_rules! plugin_types_trait_impl_config {
-    ($common_gen:ty, $gen:ty, $gen_local:ty, $gen_consensus:ty, $cfg:ty, $cfg_local:ty, $cfg_private:ty, $cfg_consensus:ty, $cfg_client:ty) => {
+    ($common_gen:ty, $gen:ty, $gen_local:ty, $gen_consensus:ty, $cfg:ty, $cfg_local:ty, $cfg_private:ty, $cfg_consensus:ty, $cfg_client:ty $(, from_erased = $from_erased:path)?) => {
         impl fedimint_core::config::ModuleGenParams for $gen {
             type Local = $gen_local;
             type Consensus = $gen_consensus;
@@ -223,6 +226,14 @@ macro_rules! plugin_types_trait_impl_config {
             fn version(&self) -> fedimint_core::module::ModuleConsensusVersion {
                 <$common_gen as fedimint_core::module::CommonModuleGen>::CONSENSUS_VERSION
             }
+
+            $(
+                fn from_erased(
+                    erased: &fedimint_core::config::ServerModuleConsensusConfig,
+                ) -> anyhow::Result<Self> {
+                    $from_erased(erased)
+                }
+            )?
         }
 
         impl fedimint_core::config::TypedServerModuleConfig for $cfg {
@@ -387,10 +387,17 @@ pub struct Feerate {
 }
 
 impl Feerate {
+    /// Fee for the given weight, rounded down to a whole satoshi
     pub fn calculate_fee(&self, weight: u64) -> bitcoin::Amount {
         let sats = self.sats_per_kvb * weight / 1000;
         bitcoin::Amount::from_sat(sats)
     }
+
+    /// Fee for the given weight without any rounding. Truncating this to whole
+    /// satoshis yields exactly [`Feerate::calculate_fee`].
+    pub fn calculate_fee_msats(&self, weight: u64) -> Amount {
+        Amount::from_msats(self.sats_per_kvb * weight)
+    }
 }
 
 #[derive(Debug, Error)]
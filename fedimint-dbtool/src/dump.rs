@@ -83,7 +83,7 @@ impl<'a> DatabaseDump<'a> {
         }
 
         let module_inits = ServerModuleGenRegistry::from(vec![
-            DynServerModuleGen::from(WalletGen),
+            DynServerModuleGen::from(WalletGen::default()),
             DynServerModuleGen::from(MintGen),
             DynServerModuleGen::from(LightningGen),
         ]);
@@ -14,6 +14,7 @@ use fedimint_core::core::{ModuleInstanceId, ModuleKind};
 use fedimint_core::module::{DynServerModuleGen, IServerModuleGen};
 use fedimint_core::task::{MaybeSend, MaybeSync, TaskGroup};
 use fedimint_logging::TracingSetup;
+use ln_gateway::ng::DEFAULT_MAX_PENDING_OPERATIONS;
 use tempfile::TempDir;
 
 use crate::btc::mock::FakeBitcoinFactory;
@@ -127,6 +128,17 @@ impl Fixtures {
 
     /// Starts a new gateway with a given lightning node
     pub async fn new_gateway(&self, ln: Box<dyn LightningTest>) -> GatewayTest {
+        self.new_gateway_with_max_pending_operations(ln, DEFAULT_MAX_PENDING_OPERATIONS)
+            .await
+    }
+
+    /// Starts a new gateway that rejects payments once
+    /// `max_pending_operations` are in flight for a federation
+    pub async fn new_gateway_with_max_pending_operations(
+        &self,
+        ln: Box<dyn LightningTest>,
+        max_pending_operations: usize,
+    ) -> GatewayTest {
         // TODO: Make construction easier
         let server_gens = ServerModuleGenRegistry::from(self.servers.clone());
         let module_kinds = self.params.iter_modules().map(|(id, kind, _)| (id, kind));
@@ -142,6 +154,7 @@ impl Fixtures {
                 // Remove LN module because the gateway adds one
                 client.to_dyn_common().module_kind() != ModuleKind::from_static_str("ln")
             })),
+            max_pending_operations,
         )
         .await
     }
@@ -11,10 +11,12 @@ use fedimint_core::module::registry::ModuleDecoderRegistry;
 use fedimint_core::task::TaskGroup;
 use lightning::routing::gossip::RoutingFees;
 use ln_gateway::client::StandardGatewayClientBuilder;
+use ln_gateway::lnrpc_client::DEFAULT_HEARTBEAT_INTERVAL;
 use ln_gateway::rpc::rpc_client::GatewayRpcClient;
 use ln_gateway::rpc::rpc_server::run_webserver;
 use ln_gateway::rpc::{ConnectFedPayload, FederationInfo};
-use ln_gateway::Gateway;
+use ln_gateway::utils::CircuitBreaker;
+use ln_gateway::{Gateway, DEFAULT_CIRCUIT_BREAKER_COOLDOWN, DEFAULT_CIRCUIT_BREAKER_FAILURES};
 use tempfile::TempDir;
 use tokio::sync::RwLock;
 use url::Url;
@@ -46,6 +48,11 @@ impl GatewayTest {
         self.gateway.remove_client(fed.id()).await.unwrap()
     }
 
+    /// Returns the gateway's client for a federation, keeping it connected
+    pub async fn select_client(&self, fed: &FederationTest) -> Client {
+        self.gateway.select_client(fed.id()).await.unwrap()
+    }
+
     /// Connects to a new federation and stores the info
     pub async fn connect_fed(&mut self, fed: &FederationTest) -> FederationInfo {
         let connect = fed.connection_code().to_string();
@@ -65,6 +72,7 @@ impl GatewayTest {
         lightning: Box<dyn LightningTest>,
         decoders: ModuleDecoderRegistry,
         registry: ClientModuleGenRegistry,
+        max_pending_operations: usize,
     ) -> Self {
         let listen: SocketAddr = format!("127.0.0.1:{base_port}").parse().unwrap();
         let address: Url = format!("http://{listen}").parse().unwrap();
@@ -72,7 +80,8 @@ impl GatewayTest {
 
         // Create federation client builder for the gateway
         let client_builder: StandardGatewayClientBuilder =
-            StandardGatewayClientBuilder::new(path.clone(), registry, 0);
+            StandardGatewayClientBuilder::new(path.clone(), registry, 0)
+                .with_max_pending_operations(max_pending_operations);
 
         let mut tg = TaskGroup::new();
         // Create the stream to route HTLCs. We cannot create the Gateway until the
@@ -91,6 +100,11 @@ impl GatewayTest {
                 proportional_millionths: 0,
             })
             .0,
+            DEFAULT_HEARTBEAT_INTERVAL,
+            CircuitBreaker::new(
+                DEFAULT_CIRCUIT_BREAKER_FAILURES,
+                DEFAULT_CIRCUIT_BREAKER_COOLDOWN,
+            ),
             Database::new(MemDatabase::new(), decoders.clone()),
             address.clone(),
             clients.clone(),
@@ -6,7 +6,7 @@ use bitcoin::hashes::{sha256, Hash};
 use bitcoin::secp256k1::{PublicKey, SecretKey};
 use bitcoin::{secp256k1, KeyPair};
 use fedimint_client_legacy::modules::ln::contracts::Preimage;
-use fedimint_core::task::TaskGroup;
+use fedimint_core::task::{sleep, TaskGroup};
 use fedimint_core::Amount;
 use futures::stream;
 use lightning::ln::PaymentSecret;
@@ -16,7 +16,7 @@ use lightning_invoice::{
 };
 use ln_gateway::gatewaylnrpc::{
     self, EmptyResponse, GetNodeInfoResponse, GetRouteHintsResponse, InterceptHtlcResponse,
-    PayInvoiceRequest, PayInvoiceResponse,
+    PayInvoiceRequest, PayInvoiceResponse, ProbeRouteRequest, ProbeRouteResponse,
 };
 use ln_gateway::lnrpc_client::{ILnRpcClient, LightningRpcError, RouteHtlcStream};
 use rand::rngs::OsRng;
@@ -24,6 +24,16 @@ use rand::rngs::OsRng;
 use super::LightningTest;
 
 pub const INVALID_INVOICE_DESCRIPTION: &str = "INVALID";
+/// `FakeLightningTest` never finishes paying invoices with this description
+pub const HOLD_INVOICE_DESCRIPTION: &str = "HOLD";
+/// `FakeLightningTest` fails to probe routes to the node with this secret key
+const PROBE_ERROR_NODE_SECRET: [u8; 32] = [0xee; 32];
+/// `FakeLightningTest` never finishes probing routes to the node with this
+/// secret key
+const PROBE_HOLD_NODE_SECRET: [u8; 32] = [0xef; 32];
+/// How long `FakeLightningTest` takes to probe a route, so concurrent probes
+/// overlap
+const PROBE_DURATION: Duration = Duration::from_millis(100);
 
 #[derive(Clone, Debug)]
 pub struct FakeLightningTest {
@@ -31,6 +41,8 @@ pub struct FakeLightningTest {
     pub gateway_node_pub_key: secp256k1::PublicKey,
     gateway_node_sec_key: secp256k1::SecretKey,
     amount_sent: Arc<Mutex<u64>>,
+    probes_in_flight: Arc<Mutex<usize>>,
+    max_probes_in_flight: Arc<Mutex<usize>>,
 }
 
 impl FakeLightningTest {
@@ -44,8 +56,46 @@ impl FakeLightningTest {
             gateway_node_sec_key: SecretKey::from_keypair(&kp),
             gateway_node_pub_key: PublicKey::from_keypair(&kp),
             amount_sent,
+            probes_in_flight: Arc::new(Mutex::new(0)),
+            max_probes_in_flight: Arc::new(Mutex::new(0)),
         }
     }
+
+    /// A node that probes fail for
+    pub fn probe_error_node() -> PublicKey {
+        Self::node_pub_key(&PROBE_ERROR_NODE_SECRET)
+    }
+
+    /// A node that probes never finish for
+    pub fn probe_hold_node() -> PublicKey {
+        Self::node_pub_key(&PROBE_HOLD_NODE_SECRET)
+    }
+
+    /// The largest number of successful probes that were in flight at the
+    /// same time
+    pub fn max_probes_in_flight(&self) -> usize {
+        *self.max_probes_in_flight.lock().unwrap()
+    }
+
+    fn node_pub_key(secret: &[u8; 32]) -> PublicKey {
+        let ctx = bitcoin::secp256k1::Secp256k1::new();
+        PublicKey::from_secret_key(&ctx, &SecretKey::from_slice(secret).unwrap())
+    }
+
+    /// Creates an invoice whose payment stays in flight forever
+    pub fn hold_invoice(&self, amount: Amount) -> Invoice {
+        let ctx = bitcoin::secp256k1::Secp256k1::new();
+
+        InvoiceBuilder::new(Currency::Regtest)
+            .description(HOLD_INVOICE_DESCRIPTION.to_string())
+            .payment_hash(sha256::Hash::hash(&[1; 32]))
+            .current_timestamp()
+            .min_final_cltv_expiry(0)
+            .payment_secret(PaymentSecret([0; 32]))
+            .amount_milli_satoshis(amount.msats)
+            .build_signed(|m| ctx.sign_ecdsa_recoverable(m, &self.gateway_node_sec_key))
+            .unwrap()
+    }
 }
 
 impl Default for FakeLightningTest {
@@ -107,6 +157,15 @@ impl ILnRpcClient for FakeLightningTest {
     ) -> Result<PayInvoiceResponse, LightningRpcError> {
         let signed = invoice.invoice.parse::<SignedRawInvoice>().unwrap();
         let invoice = Invoice::from_signed(signed).unwrap();
+
+        if invoice.description()
+            == InvoiceDescription::Direct(
+                &Description::new(HOLD_INVOICE_DESCRIPTION.into()).unwrap(),
+            )
+        {
+            std::future::pending::<()>().await;
+        }
+
         *self.amount_sent.lock().unwrap() += invoice.amount_milli_satoshis().unwrap();
 
         if invoice.description()
@@ -128,7 +187,8 @@ impl ILnRpcClient for FakeLightningTest {
         self: Box<Self>,
         _task_group: &mut TaskGroup,
     ) -> Result<(RouteHtlcStream<'a>, Arc<dyn ILnRpcClient>), LightningRpcError> {
-        Ok((Box::pin(stream::iter(vec![])), Arc::new(Self::new())))
+        // Share the counters with the instance the test holds on to
+        Ok((Box::pin(stream::iter(vec![])), Arc::new(*self)))
     }
 
     async fn complete_htlc(
@@ -137,4 +197,32 @@ impl ILnRpcClient for FakeLightningTest {
     ) -> Result<EmptyResponse, LightningRpcError> {
         Ok(EmptyResponse {})
     }
+
+    async fn probe(
+        &self,
+        probe: ProbeRouteRequest,
+    ) -> Result<ProbeRouteResponse, LightningRpcError> {
+        let dest = PublicKey::from_slice(&probe.node_pub_key).unwrap();
+
+        if dest == Self::probe_error_node() {
+            return Err(LightningRpcError::FailedToProbeRoute {
+                failure_reason: "Destination was unreachable".to_string(),
+            });
+        }
+
+        if dest == Self::probe_hold_node() {
+            std::future::pending::<()>().await;
+        }
+
+        {
+            let mut in_flight = self.probes_in_flight.lock().unwrap();
+            *in_flight += 1;
+            let mut max_in_flight = self.max_probes_in_flight.lock().unwrap();
+            *max_in_flight = (*max_in_flight).max(*in_flight);
+        }
+        sleep(PROBE_DURATION).await;
+        *self.probes_in_flight.lock().unwrap() -= 1;
+
+        Ok(ProbeRouteResponse { routable: true })
+    }
 }
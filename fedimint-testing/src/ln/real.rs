@@ -13,7 +13,7 @@ use fedimint_core::Amount;
 use lightning_invoice::Invoice;
 use ln_gateway::gatewaylnrpc::{
     EmptyResponse, GetNodeInfoResponse, GetRouteHintsResponse, InterceptHtlcResponse,
-    PayInvoiceRequest, PayInvoiceResponse,
+    PayInvoiceRequest, PayInvoiceResponse, ProbeRouteRequest, ProbeRouteResponse,
 };
 use ln_gateway::lnd::GatewayLndClient;
 use ln_gateway::lnrpc_client::{
@@ -120,6 +120,13 @@ impl ILnRpcClient for ClnLightningTest {
     ) -> Result<EmptyResponse, LightningRpcError> {
         self.lnrpc.complete_htlc(htlc).await
     }
+
+    async fn probe(
+        &self,
+        probe: ProbeRouteRequest,
+    ) -> Result<ProbeRouteResponse, LightningRpcError> {
+        self.lnrpc.probe(probe).await
+    }
 }
 
 impl ClnLightningTest {
@@ -270,6 +277,13 @@ impl ILnRpcClient for LndLightningTest {
     ) -> Result<EmptyResponse, LightningRpcError> {
         self.lnrpc.complete_htlc(htlc).await
     }
+
+    async fn probe(
+        &self,
+        probe: ProbeRouteRequest,
+    ) -> Result<ProbeRouteResponse, LightningRpcError> {
+        self.lnrpc.probe(probe).await
+    }
 }
 
 impl LndLightningTest {
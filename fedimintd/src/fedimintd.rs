@@ -96,7 +96,7 @@ pub struct ServerOpts {
 ///     Fedimintd::new()?
 ///         // use `.with_default_modules()` to avoid having
 ///         // to import these manually
-///         .with_module(WalletGen)
+///         .with_module(WalletGen::default())
 ///         .with_module(MintGen)
 ///         .with_module(LightningGen)
 ///         .run()
@@ -137,7 +137,7 @@ impl Fedimintd {
     pub fn with_default_modules(self) -> Self {
         self.with_module(LightningGen)
             .with_module(MintGen)
-            .with_module(WalletGen)
+            .with_module(WalletGen::default())
     }
 
     pub async fn run(self) -> ! {
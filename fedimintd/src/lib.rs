@@ -44,6 +44,9 @@ pub fn attach_default_module_gen_params(
                     // commit anyway
                     finality_delay,
                     client_default_bitcoin_rpc: default_esplora_server(network),
+                    output_ordering: Default::default(),
+                    allowed_address_types: None,
+                    lock_time: Default::default(),
                 },
             },
         )
@@ -21,7 +21,8 @@ use ln_gateway::gatewaylnrpc::get_route_hints_response::{RouteHint, RouteHintHop
 use ln_gateway::gatewaylnrpc::intercept_htlc_response::{Action, Cancel, Forward, Settle};
 use ln_gateway::gatewaylnrpc::{
     EmptyRequest, EmptyResponse, GetNodeInfoResponse, GetRouteHintsResponse, InterceptHtlcRequest,
-    InterceptHtlcResponse, PayInvoiceRequest, PayInvoiceResponse,
+    InterceptHtlcResponse, PayInvoiceRequest, PayInvoiceResponse, ProbeRouteRequest,
+    ProbeRouteResponse,
 };
 use secp256k1::PublicKey;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -488,6 +489,53 @@ impl GatewayLightning for ClnRpcService {
         }
         Ok(tonic::Response::new(EmptyResponse {}))
     }
+
+    async fn probe_route(
+        &self,
+        request: tonic::Request<ProbeRouteRequest>,
+    ) -> Result<tonic::Response<ProbeRouteResponse>, Status> {
+        let ProbeRouteRequest {
+            node_pub_key,
+            amount_msat,
+        } = request.into_inner();
+
+        let destination = PublicKey::from_slice(&node_pub_key)
+            .map_err(|e| Status::invalid_argument(format!("Invalid destination {e:?}")))?;
+
+        // CLN returns an error if it cannot find a route to the destination, which
+        // we report as the destination being unroutable
+        let routable = match self
+            .rpc_client()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .call(cln_rpc::Request::GetRoute(model::GetrouteRequest {
+                id: destination,
+                amount_msat: cln_rpc::primitives::Amount::from_msat(amount_msat),
+                riskfactor: 10,
+                cltv: None,
+                fromid: None,
+                fuzzpercent: None,
+                exclude: None,
+                maxhops: None,
+            }))
+            .await
+        {
+            Ok(cln_rpc::Response::GetRoute(model::GetrouteResponse { route })) => {
+                !route.is_empty()
+            }
+            Ok(_) => {
+                return Err(Status::internal(
+                    ClnExtensionError::RpcWrongResponse.to_string(),
+                ))
+            }
+            Err(e) => {
+                debug!("cln getroute could not find a route to {destination}: {e:?}");
+                false
+            }
+        };
+
+        Ok(tonic::Response::new(ProbeRouteResponse { routable }))
+    }
 }
 
 #[derive(Debug, Error)]
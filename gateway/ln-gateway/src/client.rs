@@ -10,12 +10,16 @@ use fedimint_core::api::{DynGlobalApi, GlobalFederationApi, WsClientConnectInfo,
 use fedimint_core::core::ModuleInstanceId;
 use fedimint_core::db::DatabaseTransaction;
 use fedimint_core::task::TaskGroup;
+use fedimint_core::Amount;
 use futures::StreamExt;
 use lightning::routing::gossip::RoutingFees;
 
 use crate::db::{FederationConfig, FederationIdKey, FederationIdKeyPrefix};
-use crate::lnrpc_client::ILnRpcClient;
-use crate::ng::GatewayClientGen;
+use crate::lnrpc_client::{ILnRpcClient, LnRpcHeartbeat, ReconnectBackoff};
+use crate::ng::{
+    GatewayClientGen, DEFAULT_MAX_INVOICE_CLTV, DEFAULT_MAX_PAYMENT,
+    DEFAULT_MAX_PENDING_OPERATIONS, DEFAULT_MIN_PAYMENT,
+};
 use crate::{GatewayError, Result};
 
 #[derive(Debug, Clone)]
@@ -23,6 +27,11 @@ pub struct StandardGatewayClientBuilder {
     work_dir: PathBuf,
     registry: ClientModuleGenRegistry,
     primary_module: ModuleInstanceId,
+    max_pending_operations: usize,
+    min_payment: Amount,
+    max_payment: Amount,
+    max_invoice_cltv: u64,
+    reconnect_backoff: ReconnectBackoff,
 }
 
 impl StandardGatewayClientBuilder {
@@ -35,8 +44,42 @@ impl StandardGatewayClientBuilder {
             work_dir,
             registry,
             primary_module,
+            max_pending_operations: DEFAULT_MAX_PENDING_OPERATIONS,
+            min_payment: DEFAULT_MIN_PAYMENT,
+            max_payment: DEFAULT_MAX_PAYMENT,
+            max_invoice_cltv: DEFAULT_MAX_INVOICE_CLTV,
+            reconnect_backoff: ReconnectBackoff::default(),
         }
     }
+
+    /// Sets how many pay operations every client built afterwards may have in
+    /// flight before it rejects new payments
+    pub fn with_max_pending_operations(mut self, max_pending_operations: usize) -> Self {
+        self.max_pending_operations = max_pending_operations;
+        self
+    }
+
+    /// Sets the smallest and largest invoice amount every client built
+    /// afterwards is willing to pay
+    pub fn with_payment_limits(mut self, min_payment: Amount, max_payment: Amount) -> Self {
+        self.min_payment = min_payment;
+        self.max_payment = max_payment;
+        self
+    }
+
+    /// Sets the largest CLTV delta of an invoice, including our own timelock
+    /// delta, every client built afterwards is willing to pay
+    pub fn with_max_invoice_cltv(mut self, max_invoice_cltv: u64) -> Self {
+        self.max_invoice_cltv = max_invoice_cltv;
+        self
+    }
+
+    /// Sets how every client built afterwards reconnects to the lightning node
+    /// when a payment can't reach it
+    pub fn with_reconnect_backoff(mut self, reconnect_backoff: ReconnectBackoff) -> Self {
+        self.reconnect_backoff = reconnect_backoff;
+        self
+    }
 }
 
 impl StandardGatewayClientBuilder {
@@ -45,6 +88,7 @@ impl StandardGatewayClientBuilder {
         config: FederationConfig,
         node_pub_key: secp256k1::PublicKey,
         lnrpc: Arc<dyn ILnRpcClient>,
+        heartbeat: Arc<LnRpcHeartbeat>,
         tg: TaskGroup,
         old_client: Option<fedimint_client::Client>,
     ) -> Result<fedimint_client::Client> {
@@ -53,10 +97,16 @@ impl StandardGatewayClientBuilder {
         let mut registry = self.registry.clone();
         registry.attach(GatewayClientGen {
             lnrpc,
+            heartbeat,
             node_pub_key,
             fees: config.fees,
             timelock_delta: config.timelock_delta,
             mint_channel_id: config.mint_channel_id,
+            max_pending_operations: self.max_pending_operations,
+            min_payment: self.min_payment,
+            max_payment: self.max_payment,
+            max_invoice_cltv: self.max_invoice_cltv,
+            reconnect_backoff: self.reconnect_backoff,
         });
 
         let mut client_builder = ClientBuilder::default();
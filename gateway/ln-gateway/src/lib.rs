@@ -40,7 +40,7 @@ use fedimint_core::module::CommonModuleGen;
 use fedimint_core::task::{sleep, RwLock, TaskGroup, TaskHandle};
 use fedimint_core::time::now;
 use fedimint_core::Amount;
-use fedimint_ln_client::contracts::Preimage;
+use fedimint_ln_client::contracts::{ContractId, Preimage};
 use fedimint_ln_client::pay::PayInvoicePayload;
 use fedimint_ln_common::config::GatewayFee;
 use fedimint_ln_common::route_hints::RouteHint;
@@ -51,9 +51,9 @@ use futures::stream::StreamExt;
 use gatewaylnrpc::intercept_htlc_response::Action;
 use gatewaylnrpc::{GetNodeInfoResponse, InterceptHtlcResponse};
 use lightning::routing::gossip::RoutingFees;
-use lnrpc_client::{ILnRpcClient, LightningRpcError, RouteHtlcStream};
+use lnrpc_client::{ILnRpcClient, LightningRpcError, LnRpcHeartbeat, RouteHtlcStream};
 use ng::pay::OutgoingPaymentError;
-use ng::GatewayClientExt;
+use ng::{GatewayClientExt, DEFAULT_MAX_PAYMENT, DEFAULT_MIN_PAYMENT};
 use rand::rngs::OsRng;
 use rand::Rng;
 use rpc::FederationInfo;
@@ -66,13 +66,14 @@ use url::Url;
 
 use crate::gatewaylnrpc::intercept_htlc_response::Forward;
 use crate::lnd::GatewayLndClient;
-use crate::lnrpc_client::NetworkLnRpcClient;
+use crate::lnrpc_client::{NetworkLnRpcClient, ReconnectBackoff, DEFAULT_HEARTBEAT_INTERVAL};
 use crate::ng::GatewayExtPayStates;
 use crate::rpc::rpc_server::run_webserver;
 use crate::rpc::{
     BackupPayload, BalancePayload, ConnectFedPayload, DepositAddressPayload, GatewayInfo,
     InfoPayload, RestorePayload, WithdrawPayload,
 };
+use crate::utils::{CircuitBreaker, CircuitBreakerState};
 
 /// LND HTLC interceptor can't handle SCID of 0, so start from 1
 const INITIAL_SCID: u64 = 1;
@@ -80,6 +81,13 @@ const INITIAL_SCID: u64 = 1;
 /// How long a gateway announcement stays valid
 pub const GW_ANNOUNCEMENT_TTL: Duration = Duration::from_secs(600);
 
+/// Consecutive failed payments after which a federation stops accepting new
+/// payments for a while
+pub const DEFAULT_CIRCUIT_BREAKER_FAILURES: u32 = 10;
+/// How long a federation stops accepting payments after its circuit breaker
+/// opened
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(300);
+
 const ROUTE_HINT_RETRIES: usize = 10;
 const ROUTE_HINT_RETRY_SLEEP: Duration = Duration::from_secs(2);
 
@@ -125,6 +133,71 @@ pub struct GatewayOpts {
     /// Format: <base_msat>,<proportional_millionths>
     #[arg(long = "fees", env = "FM_GATEWAY_FEES")]
     pub fees: Option<GatewayFee>,
+
+    /// Seconds between two liveness checks of the lightning node
+    #[arg(long = "heartbeat-interval", env = "FM_GATEWAY_HEARTBEAT_INTERVAL")]
+    pub heartbeat_interval: Option<u64>,
+
+    /// Maximum number of payments per federation that may be in flight before
+    /// new ones are rejected
+    #[arg(
+        long = "max-pending-operations",
+        env = "FM_GATEWAY_MAX_PENDING_OPERATIONS"
+    )]
+    pub max_pending_operations: Option<usize>,
+
+    /// Smallest invoice amount in msat the gateway is willing to pay
+    #[arg(long = "min-payment-msat", env = "FM_GATEWAY_MIN_PAYMENT_MSAT")]
+    pub min_payment_msat: Option<u64>,
+
+    /// Largest invoice amount in msat the gateway is willing to pay
+    #[arg(long = "max-payment-msat", env = "FM_GATEWAY_MAX_PAYMENT_MSAT")]
+    pub max_payment_msat: Option<u64>,
+
+    /// Largest CLTV delta in blocks, including the federation's timelock
+    /// delta, of an invoice the gateway is willing to pay
+    #[arg(long = "max-invoice-cltv", env = "FM_GATEWAY_MAX_INVOICE_CLTV")]
+    pub max_invoice_cltv: Option<u64>,
+
+    /// Seconds before the first attempt to reconnect to an unreachable
+    /// lightning node, doubled after every failed attempt
+    #[arg(
+        long = "lightning-reconnect-delay",
+        env = "FM_GATEWAY_LIGHTNING_RECONNECT_DELAY"
+    )]
+    pub lightning_reconnect_delay: Option<u64>,
+
+    /// Maximum seconds between two attempts to reconnect to an unreachable
+    /// lightning node
+    #[arg(
+        long = "lightning-reconnect-max-delay",
+        env = "FM_GATEWAY_LIGHTNING_RECONNECT_MAX_DELAY"
+    )]
+    pub lightning_reconnect_max_delay: Option<u64>,
+
+    /// Attempts to reconnect to an unreachable lightning node before a payment
+    /// fails
+    #[arg(
+        long = "lightning-reconnect-attempts",
+        env = "FM_GATEWAY_LIGHTNING_RECONNECT_ATTEMPTS"
+    )]
+    pub lightning_reconnect_attempts: Option<u32>,
+
+    /// Consecutive payments the lightning node failed to make after which a
+    /// federation temporarily stops accepting new payments
+    #[arg(
+        long = "circuit-breaker-failures",
+        env = "FM_GATEWAY_CIRCUIT_BREAKER_FAILURES",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub circuit_breaker_failures: Option<u32>,
+
+    /// Seconds a federation stops accepting payments after repeated failures
+    #[arg(
+        long = "circuit-breaker-cooldown",
+        env = "FM_GATEWAY_CIRCUIT_BREAKER_COOLDOWN"
+    )]
+    pub circuit_breaker_cooldown: Option<u64>,
 }
 
 pub struct Gatewayd {
@@ -135,6 +208,16 @@ pub struct Gatewayd {
     api_addr: Url,
     password: String,
     fees: Option<GatewayFee>,
+    heartbeat_interval: Option<u64>,
+    max_pending_operations: Option<usize>,
+    min_payment_msat: Option<u64>,
+    max_payment_msat: Option<u64>,
+    max_invoice_cltv: Option<u64>,
+    lightning_reconnect_delay: Option<u64>,
+    lightning_reconnect_max_delay: Option<u64>,
+    lightning_reconnect_attempts: Option<u32>,
+    circuit_breaker_failures: Option<u32>,
+    circuit_breaker_cooldown: Option<u64>,
 }
 
 impl Gatewayd {
@@ -156,8 +239,26 @@ impl Gatewayd {
             api_addr,
             password,
             fees,
+            heartbeat_interval,
+            max_pending_operations,
+            min_payment_msat,
+            max_payment_msat,
+            max_invoice_cltv,
+            lightning_reconnect_delay,
+            lightning_reconnect_max_delay,
+            lightning_reconnect_attempts,
+            circuit_breaker_failures,
+            circuit_breaker_cooldown,
         } = GatewayOpts::parse();
 
+        let min_payment = min_payment_msat.map_or(DEFAULT_MIN_PAYMENT, Amount::from_msats);
+        let max_payment = max_payment_msat.map_or(DEFAULT_MAX_PAYMENT, Amount::from_msats);
+        if min_payment > max_payment {
+            anyhow::bail!(
+                "Minimum payment {min_payment} exceeds the maximum payment {max_payment}, check --min-payment-msat and --max-payment-msat"
+            );
+        }
+
         info!(
             "Starting gatewayd (version: {})",
             env!("FEDIMINT_BUILD_CODE_VERSION")
@@ -171,6 +272,16 @@ impl Gatewayd {
             api_addr,
             password,
             fees,
+            heartbeat_interval,
+            max_pending_operations,
+            min_payment_msat,
+            max_payment_msat,
+            max_invoice_cltv,
+            lightning_reconnect_delay,
+            lightning_reconnect_max_delay,
+            lightning_reconnect_attempts,
+            circuit_breaker_failures,
+            circuit_breaker_cooldown,
         })
     }
 
@@ -196,11 +307,37 @@ impl Gatewayd {
             .registry
             .decoders(DEFAULT_MODULE_KINDS.iter().cloned())?;
 
-        let client_builder = StandardGatewayClientBuilder::new(
+        let mut client_builder = StandardGatewayClientBuilder::new(
             self.data_dir.clone(),
             self.registry.clone(),
             LEGACY_HARDCODED_INSTANCE_ID_MINT,
         );
+        if let Some(max_pending_operations) = self.max_pending_operations {
+            client_builder = client_builder.with_max_pending_operations(max_pending_operations);
+        }
+        if self.min_payment_msat.is_some() || self.max_payment_msat.is_some() {
+            client_builder = client_builder.with_payment_limits(
+                self.min_payment_msat
+                    .map_or(DEFAULT_MIN_PAYMENT, Amount::from_msats),
+                self.max_payment_msat
+                    .map_or(DEFAULT_MAX_PAYMENT, Amount::from_msats),
+            );
+        }
+        if let Some(max_invoice_cltv) = self.max_invoice_cltv {
+            client_builder = client_builder.with_max_invoice_cltv(max_invoice_cltv);
+        }
+        let default_backoff = ReconnectBackoff::default();
+        client_builder = client_builder.with_reconnect_backoff(ReconnectBackoff {
+            initial_delay: self
+                .lightning_reconnect_delay
+                .map_or(default_backoff.initial_delay, Duration::from_secs),
+            max_delay: self
+                .lightning_reconnect_max_delay
+                .map_or(default_backoff.max_delay, Duration::from_secs),
+            max_attempts: self
+                .lightning_reconnect_attempts
+                .unwrap_or(default_backoff.max_attempts),
+        });
 
         let db = Database::new(
             fedimint_rocksdb::RocksDb::open(self.data_dir.join(DB_FILE))?,
@@ -249,6 +386,11 @@ impl Gatewayd {
                                     ln_client.clone(),
                                     client_builder.clone(),
                                     self.fees.clone().unwrap_or(GatewayFee(DEFAULT_FEES)).0,
+                                    self.heartbeat_interval.map(Duration::from_secs).unwrap_or(DEFAULT_HEARTBEAT_INTERVAL),
+                                    CircuitBreaker::new(
+                                        self.circuit_breaker_failures.unwrap_or(DEFAULT_CIRCUIT_BREAKER_FAILURES),
+                                        self.circuit_breaker_cooldown.map(Duration::from_secs).unwrap_or(DEFAULT_CIRCUIT_BREAKER_COOLDOWN),
+                                    ),
                                     database.clone(),
                                     self.api_addr.clone(),
                                     clients.clone(),
@@ -348,6 +490,8 @@ pub enum GatewayError {
     InvalidMetadata(String),
     #[error("Unexpected state: {0}")]
     UnexpectedState(String),
+    #[error("Federation {0} is not accepting payments after repeated failures")]
+    CircuitBreakerOpen(FederationId),
 }
 
 impl IntoResponse for GatewayError {
@@ -361,6 +505,10 @@ impl IntoResponse for GatewayError {
                     .to_string(),
                 StatusCode::BAD_REQUEST,
             ),
+            GatewayError::CircuitBreakerOpen(_) => (
+                "Gateway is temporarily not accepting payments for this federation".to_string(),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
             _ => (
                 "An internal gateway error occurred".to_string(),
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -375,7 +523,13 @@ impl IntoResponse for GatewayError {
 #[derive(Clone)]
 pub struct Gateway {
     lnrpc: Arc<dyn ILnRpcClient>,
+    heartbeat: Arc<LnRpcHeartbeat>,
+    /// Template for the circuit breaker of each federation
+    circuit_breaker: CircuitBreaker,
+    circuit_breakers: Arc<Mutex<BTreeMap<FederationId, CircuitBreaker>>>,
     clients: Arc<RwLock<BTreeMap<FederationId, fedimint_client::Client>>>,
+    /// Route hints each federation was last registered with
+    registered_route_hints: Arc<Mutex<BTreeMap<FederationId, Vec<RouteHint>>>>,
     scid_to_federation: Arc<RwLock<BTreeMap<u64, FederationId>>>,
     client_builder: StandardGatewayClientBuilder,
     channel_id_generator: Arc<Mutex<AtomicU64>>,
@@ -392,6 +546,8 @@ impl Gateway {
         lnrpc: Arc<dyn ILnRpcClient>,
         client_builder: StandardGatewayClientBuilder,
         fees: RoutingFees,
+        heartbeat_interval: Duration,
+        circuit_breaker: CircuitBreaker,
         gatewayd_db: Database,
         api: Url,
         clients: Arc<RwLock<BTreeMap<FederationId, fedimint_client::Client>>>,
@@ -400,7 +556,11 @@ impl Gateway {
     ) -> Result<Self> {
         let mut gw = Self {
             lnrpc,
+            heartbeat: Arc::new(LnRpcHeartbeat::default()),
+            circuit_breaker,
+            circuit_breakers: Arc::new(Mutex::new(BTreeMap::new())),
             clients,
+            registered_route_hints: Arc::new(Mutex::new(BTreeMap::new())),
             scid_to_federation,
             client_builder,
             channel_id_generator: Arc::new(Mutex::new(AtomicU64::new(INITIAL_SCID))),
@@ -411,6 +571,7 @@ impl Gateway {
             gateway_id: Self::get_gateway_id(gatewayd_db).await,
         };
 
+        gw.start_heartbeat(heartbeat_interval).await;
         gw.register_clients_timer().await;
         gw.load_clients().await?;
         Ok(gw)
@@ -504,8 +665,24 @@ impl Gateway {
         Ok((route_hints, node_pub_key, alias))
     }
 
+    async fn start_heartbeat(&mut self, interval: Duration) {
+        let heartbeat = self.heartbeat.clone();
+        let lnrpc = self.lnrpc.clone();
+        self.task_group
+            .spawn("lightning node heartbeat", move |handle| async move {
+                heartbeat.run(lnrpc, interval, handle).await;
+            })
+            .await;
+    }
+
+    /// Whether the lightning node answered its recent liveness checks
+    pub fn node_connected(&self) -> bool {
+        self.heartbeat.node_connected()
+    }
+
     async fn register_clients_timer(&mut self) {
         let clients = self.clients.clone();
+        let registered_route_hints = self.registered_route_hints.clone();
         let api = self.api.clone();
         let lnrpc = self.lnrpc.clone();
         let gateway_id = self.gateway_id;
@@ -526,6 +703,11 @@ impl Gateway {
                                     .is_err()
                                 {
                                     error!("Error registering federation {federation_id}");
+                                } else {
+                                    registered_route_hints
+                                        .lock()
+                                        .await
+                                        .insert(*federation_id, route_hints.clone());
                                 }
                             }
                         }
@@ -561,6 +743,7 @@ impl Gateway {
                         config.clone(),
                         node_pub_key,
                         self.lnrpc.clone(),
+                        self.heartbeat.clone(),
                         self.task_group.make_subgroup().await,
                         old_client,
                     )
@@ -595,11 +778,15 @@ impl Gateway {
         client
             .register_with_federation(
                 self.api.clone(),
-                route_hints,
+                route_hints.clone(),
                 GW_ANNOUNCEMENT_TTL,
                 self.gateway_id,
             )
             .await?;
+        self.registered_route_hints
+            .lock()
+            .await
+            .insert(federation_id, route_hints);
         self.clients.write().await.insert(federation_id, client);
         self.scid_to_federation
             .write()
@@ -608,6 +795,50 @@ impl Gateway {
         Ok(())
     }
 
+    /// Fetches the route hints from the lightning node once and re-registers
+    /// with every federation whose registration carries different hints, e.g.
+    /// after a new private channel was opened. Federations that fail to
+    /// register are retried on the next call or by the registration timer.
+    pub async fn refresh_route_hints_all(&self) -> Result<()> {
+        let route_hints: Vec<RouteHint> = self.lnrpc.routehints().await?.try_into()?;
+
+        let mut failed = vec![];
+        for (federation_id, client) in self.clients.read().await.iter() {
+            if self.registered_route_hints.lock().await.get(federation_id) == Some(&route_hints) {
+                continue;
+            }
+
+            match client
+                .register_with_federation(
+                    self.api.clone(),
+                    route_hints.clone(),
+                    GW_ANNOUNCEMENT_TTL,
+                    self.gateway_id,
+                )
+                .await
+            {
+                Ok(()) => {
+                    self.registered_route_hints
+                        .lock()
+                        .await
+                        .insert(*federation_id, route_hints.clone());
+                }
+                Err(e) => {
+                    error!("Error refreshing route hints with federation {federation_id}: {e:?}");
+                    failed.push(*federation_id);
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(GatewayError::UnexpectedState(format!(
+                "Could not refresh route hints with federations {failed:?}"
+            )))
+        }
+    }
+
     pub async fn remove_client(
         &self,
         federation_id: FederationId,
@@ -615,6 +846,10 @@ impl Gateway {
         let client = self.clients.write().await.remove(&federation_id).ok_or(
             GatewayError::InvalidMetadata(format!("No federation with id {federation_id}")),
         )?;
+        self.registered_route_hints
+            .lock()
+            .await
+            .remove(&federation_id);
         let mut dbtx = self.gatewayd_db.begin_transaction().await;
         dbtx.remove_entry(&FederationRegistrationKey { id: federation_id })
             .await;
@@ -683,6 +918,7 @@ impl Gateway {
                 gw_client_cfg.clone(),
                 node_pub_key,
                 self.lnrpc.clone(),
+                self.heartbeat.clone(),
                 self.task_group.make_subgroup().await,
                 old_client,
             )
@@ -701,6 +937,7 @@ impl Gateway {
         Ok(FederationInfo {
             federation_id,
             balance_msat,
+            circuit_breaker: CircuitBreakerState::Closed,
         })
     }
 
@@ -709,12 +946,14 @@ impl Gateway {
         let federation_clients = self.clients.read().await.clone().into_iter();
         let (route_hints, node_pub_key, alias) =
             Self::fetch_lightning_route_info(self.lnrpc.clone()).await?;
+        let mut circuit_breakers = self.circuit_breaker_states().await;
         for (federation_id, client) in federation_clients {
             let balance_msat = client.get_balance().await;
 
             federations.push(FederationInfo {
                 federation_id,
                 balance_msat,
+                circuit_breaker: circuit_breakers.remove(&federation_id).unwrap_or_default(),
             });
         }
 
@@ -729,12 +968,59 @@ impl Gateway {
         })
     }
 
+    /// Returns the circuit breaker state of every federation that had a
+    /// payment routed through this gateway
+    pub async fn circuit_breaker_states(&self) -> BTreeMap<FederationId, CircuitBreakerState> {
+        self.circuit_breakers
+            .lock()
+            .await
+            .iter()
+            .map(|(federation_id, breaker)| (*federation_id, breaker.state()))
+            .collect()
+    }
+
     async fn handle_pay_invoice_msg(&self, payload: PayInvoicePayload) -> Result<Preimage> {
         let PayInvoicePayload {
             federation_id,
             contract_id,
         } = payload;
 
+        let allows_requests = self
+            .circuit_breakers
+            .lock()
+            .await
+            .get(&federation_id)
+            .map_or(true, CircuitBreaker::allows_requests);
+        if !allows_requests {
+            return Err(GatewayError::CircuitBreakerOpen(federation_id));
+        }
+
+        let result = self.pay_invoice(federation_id, contract_id).await;
+
+        let mut circuit_breakers = self.circuit_breakers.lock().await;
+        let breaker = circuit_breakers
+            .entry(federation_id)
+            .or_insert_with(|| self.circuit_breaker.clone());
+        // Only failures of the lightning node count, contracts that are invalid or
+        // missing are the fault of the payer
+        match &result {
+            Ok(_) => breaker.record_success(),
+            Err(GatewayError::OutgoingPaymentError(error))
+                if matches!(**error, OutgoingPaymentError::LightningPayError { .. }) =>
+            {
+                breaker.record_failure()
+            }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    async fn pay_invoice(
+        &self,
+        federation_id: FederationId,
+        contract_id: ContractId,
+    ) -> Result<Preimage> {
         let client = self.select_client(federation_id).await?;
         let operation_id = client.gateway_pay_bolt11_invoice(contract_id).await?;
         let mut updates = client
@@ -755,7 +1041,7 @@ impl Gateway {
                     error!(error_message);
                     return Err(GatewayError::OutgoingPaymentError(Box::new(error)));
                 }
-                GatewayExtPayStates::Canceled { error } => {
+                GatewayExtPayStates::Canceled { error, .. } => {
                     return Err(GatewayError::OutgoingPaymentError(Box::new(error)));
                 }
                 _ => {}
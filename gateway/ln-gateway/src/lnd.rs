@@ -11,7 +11,7 @@ use tokio_stream::wrappers::ReceiverStream;
 use tonic::Status;
 use tonic_lnd::lnrpc::failure::FailureCode;
 use tonic_lnd::lnrpc::payment::PaymentStatus;
-use tonic_lnd::lnrpc::{ChanInfoRequest, GetInfoRequest, ListChannelsRequest};
+use tonic_lnd::lnrpc::{ChanInfoRequest, GetInfoRequest, ListChannelsRequest, QueryRoutesRequest};
 use tonic_lnd::routerrpc::{
     CircuitKey, ForwardHtlcInterceptResponse, ResolveHoldForwardAction, SendPaymentRequest,
     TrackPaymentRequest,
@@ -24,7 +24,8 @@ use crate::gatewaylnrpc::get_route_hints_response::{RouteHint, RouteHintHop};
 use crate::gatewaylnrpc::intercept_htlc_response::{Action, Cancel, Forward, Settle};
 use crate::gatewaylnrpc::{
     EmptyResponse, GetNodeInfoResponse, GetRouteHintsResponse, InterceptHtlcRequest,
-    InterceptHtlcResponse, PayInvoiceRequest, PayInvoiceResponse,
+    InterceptHtlcResponse, PayInvoiceRequest, PayInvoiceResponse, ProbeRouteRequest,
+    ProbeRouteResponse,
 };
 use crate::lnrpc_client::{
     ILnRpcClient, LightningRpcError, RouteHtlcStream, MAX_LIGHTNING_RETRIES,
@@ -516,4 +517,65 @@ impl ILnRpcClient for GatewayLndClient {
             failure_reason: "Gatewayd has not started to route HTLCs".to_string(),
         })
     }
+
+    async fn probe(
+        &self,
+        probe: ProbeRouteRequest,
+    ) -> Result<ProbeRouteResponse, LightningRpcError> {
+        let ProbeRouteRequest {
+            node_pub_key,
+            amount_msat,
+        } = probe;
+
+        let pub_key = PublicKey::from_slice(&node_pub_key).map_err(|e| {
+            LightningRpcError::FailedToProbeRoute {
+                failure_reason: format!("Invalid destination public key {e:?}"),
+            }
+        })?;
+        let amt_msat: i64 =
+            amount_msat
+                .try_into()
+                .map_err(|error| LightningRpcError::FailedToProbeRoute {
+                    failure_reason: format!("amount_msat exceeds valid LND ranges {error:?}"),
+                })?;
+
+        let mut client = Self::connect(
+            self.address.clone(),
+            self.tls_cert.clone(),
+            self.macaroon.clone(),
+        )
+        .await?;
+
+        // LND returns an error if no path to the destination can be found, which we
+        // report as the destination being unroutable
+        let routable = match client
+            .lightning()
+            .query_routes(QueryRoutesRequest {
+                pub_key: pub_key.to_string(),
+                amt_msat,
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(response) => !response.into_inner().routes.is_empty(),
+            Err(status) => {
+                debug!("LND could not find a route to {pub_key}: {status:?}");
+                false
+            }
+        };
+
+        Ok(ProbeRouteResponse { routable })
+    }
+
+    async fn reconnect(&self) -> Result<(), LightningRpcError> {
+        // Every call opens its own connection, so only check that LND is reachable
+        // again
+        Self::connect(
+            self.address.clone(),
+            self.tls_cert.clone(),
+            self.macaroon.clone(),
+        )
+        .await
+        .map(|_| ())
+    }
 }
@@ -1,28 +1,69 @@
 use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
 use fedimint_core::encoding::{Decodable, Encodable};
-use fedimint_core::task::{sleep, TaskGroup};
+use fedimint_core::task::{sleep, TaskGroup, TaskHandle};
 use futures::stream::BoxStream;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tonic::transport::{Channel, Endpoint};
 use tonic::Request;
-use tracing::info;
+use tracing::{info, warn};
 use url::Url;
 
 use crate::gatewaylnrpc::gateway_lightning_client::GatewayLightningClient;
 use crate::gatewaylnrpc::{
     EmptyRequest, EmptyResponse, GetNodeInfoResponse, GetRouteHintsResponse, InterceptHtlcRequest,
-    InterceptHtlcResponse, PayInvoiceRequest, PayInvoiceResponse,
+    InterceptHtlcResponse, PayInvoiceRequest, PayInvoiceResponse, ProbeRouteRequest,
+    ProbeRouteResponse,
 };
 pub type RouteHtlcStream<'a> =
     BoxStream<'a, std::result::Result<InterceptHtlcRequest, tonic::Status>>;
 
 pub const MAX_LIGHTNING_RETRIES: u32 = 10;
 
+/// Default interval between two liveness checks of the lightning node
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Number of consecutive failed liveness checks after which the lightning node
+/// is considered offline
+pub const MAX_HEARTBEAT_FAILURES: u32 = 3;
+
+/// How often and how patiently the gateway tries to reconnect to the lightning
+/// node when a call fails with [`LightningRpcError::FailedToConnect`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectBackoff {
+    /// Delay before the first reconnect, doubled after every failed attempt
+    pub initial_delay: Duration,
+    /// Upper bound for the delay between two attempts
+    pub max_delay: Duration,
+    /// Number of reconnects before the call's error is returned
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        ReconnectBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    /// Delay before the reconnect following `attempt` previous ones
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
 #[derive(Error, Debug, Serialize, Deserialize, Encodable, Decodable, Clone, Eq, PartialEq)]
 pub enum LightningRpcError {
     #[error("Failed to connect to Lightning node")]
@@ -37,6 +78,8 @@ pub enum LightningRpcError {
     FailedToRouteHtlcs { failure_reason: String },
     #[error("Failed to complete HTLC: {failure_reason}")]
     FailedToCompleteHtlc { failure_reason: String },
+    #[error("Failed to probe route: {failure_reason}")]
+    FailedToProbeRoute { failure_reason: String },
 }
 
 #[async_trait]
@@ -67,6 +110,53 @@ pub trait ILnRpcClient: Debug + Send + Sync {
         &self,
         htlc: InterceptHtlcResponse,
     ) -> Result<EmptyResponse, LightningRpcError>;
+
+    /// Check whether the lightning node can find a route to a destination
+    /// without making a payment
+    async fn probe(
+        &self,
+        probe: ProbeRouteRequest,
+    ) -> Result<ProbeRouteResponse, LightningRpcError>;
+
+    /// Re-establish the connection to the lightning node after a call failed
+    /// with [`LightningRpcError::FailedToConnect`]. Clients that don't hold on
+    /// to a connection have nothing to do.
+    async fn reconnect(&self) -> Result<(), LightningRpcError> {
+        Ok(())
+    }
+}
+
+/// Runs `call` against `lnrpc`, reconnecting according to `backoff` as long as
+/// it fails because the lightning node can't be reached. Any other error is
+/// returned right away, so calls that reached the node are never repeated.
+pub async fn call_with_reconnect<T, F, Fut>(
+    lnrpc: &dyn ILnRpcClient,
+    backoff: &ReconnectBackoff,
+    call: F,
+) -> Result<T, LightningRpcError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, LightningRpcError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match call().await {
+            Err(LightningRpcError::FailedToConnect) if attempt < backoff.max_attempts => {
+                let delay = backoff.delay(attempt);
+                attempt += 1;
+                warn!(
+                    ?attempt,
+                    "Lost connection to the lightning node, reconnecting in {delay:?}"
+                );
+                sleep(delay).await;
+
+                if let Err(e) = lnrpc.reconnect().await {
+                    warn!(?attempt, "Failed to reconnect to the lightning node: {e:?}");
+                }
+            }
+            result => return result,
+        }
+    }
 }
 
 /// An `ILnRpcClient` that wraps around `GatewayLightningClient` for
@@ -183,4 +273,94 @@ impl ILnRpcClient for NetworkLnRpcClient {
         })?;
         Ok(res.into_inner())
     }
+
+    async fn probe(
+        &self,
+        probe: ProbeRouteRequest,
+    ) -> Result<ProbeRouteResponse, LightningRpcError> {
+        let mut client = Self::connect(self.connection_url.clone()).await?;
+        let res = client.probe_route(probe).await.map_err(|status| {
+            LightningRpcError::FailedToProbeRoute {
+                failure_reason: status.message().to_string(),
+            }
+        })?;
+        Ok(res.into_inner())
+    }
+
+    async fn reconnect(&self) -> Result<(), LightningRpcError> {
+        // Every call opens its own connection, so only check that the extension
+        // is reachable again
+        Self::connect(self.connection_url.clone()).await.map(|_| ())
+    }
+}
+
+/// Tracks whether the lightning node behind an `ILnRpcClient` is reachable by
+/// periodically requesting its node info, so that the gateway notices a dead
+/// node before it tries to pay an invoice.
+#[derive(Debug, Default)]
+pub struct LnRpcHeartbeat {
+    consecutive_failures: AtomicU32,
+}
+
+impl LnRpcHeartbeat {
+    /// Returns false if the last [`MAX_HEARTBEAT_FAILURES`] liveness checks
+    /// all failed
+    pub fn node_connected(&self) -> bool {
+        self.consecutive_failures.load(Ordering::SeqCst) < MAX_HEARTBEAT_FAILURES
+    }
+
+    /// Checks the node's liveness every `interval` until the task is shut down
+    pub async fn run(
+        self: Arc<Self>,
+        lnrpc: Arc<dyn ILnRpcClient>,
+        interval: Duration,
+        handle: TaskHandle,
+    ) {
+        while !handle.is_shutting_down() {
+            match lnrpc.info().await {
+                Ok(_) => {
+                    let failures = self.consecutive_failures.swap(0, Ordering::SeqCst);
+                    if failures >= MAX_HEARTBEAT_FAILURES {
+                        info!("Lightning node is reachable again");
+                    }
+                }
+                Err(e) => {
+                    let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+                    warn!(?failures, "Lightning node heartbeat failed: {e:?}");
+
+                    if e == LightningRpcError::FailedToConnect {
+                        if let Err(e) = lnrpc.reconnect().await {
+                            warn!(
+                                ?failures,
+                                "Failed to reconnect to the lightning node: {e:?}"
+                            );
+                        }
+                    }
+                }
+            }
+            sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ReconnectBackoff;
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_max_delay() {
+        let backoff = ReconnectBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            max_attempts: 10,
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(1), Duration::from_secs(2));
+        assert_eq!(backoff.delay(2), Duration::from_secs(4));
+        assert_eq!(backoff.delay(3), Duration::from_secs(5));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(5));
+    }
 }
@@ -0,0 +1,28 @@
+use fedimint_core::encoding::{Decodable, Encodable};
+use fedimint_core::impl_db_record;
+
+/// Prefixes of the records the gateway client module keeps in its own
+/// database, separate from gatewayd's [`crate::db::DbKeyPrefix`]
+#[repr(u8)]
+#[derive(Clone, Debug)]
+pub enum DbKeyPrefix {
+    RedeemKeyDerivation = 0x29,
+}
+
+/// Stored in the gateway client module's database to detect restoring the
+/// module with a secret that derives a different redeem key
+#[derive(Debug, Clone, Eq, PartialEq, Encodable, Decodable)]
+pub struct RedeemKeyDerivationKey;
+
+#[derive(Debug, Clone, Eq, PartialEq, Encodable, Decodable)]
+pub struct RedeemKeyDerivation {
+    /// Child of the module root secret the redeem key is derived from
+    pub child_id: u64,
+    pub redeem_pub_key: secp256k1::PublicKey,
+}
+
+impl_db_record!(
+    key = RedeemKeyDerivationKey,
+    value = RedeemKeyDerivation,
+    db_prefix = DbKeyPrefix::RedeemKeyDerivation,
+);
@@ -1,8 +1,9 @@
 pub mod complete;
+pub mod db;
 pub mod pay;
 
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use async_stream::stream;
 use bitcoin_hashes::{sha256, Hash};
@@ -22,6 +23,7 @@ use fedimint_core::encoding::{Decodable, Encodable};
 use fedimint_core::module::{
     ApiVersion, ExtendsCommonModuleGen, MultiApiVersion, TransactionItemAmount,
 };
+use fedimint_core::task::timeout;
 use fedimint_core::{apply, async_trait_maybe_send, Amount, OutPoint, TransactionId};
 use fedimint_ln_client::contracts::ContractId;
 use fedimint_ln_common::api::LnFederationApi;
@@ -35,27 +37,53 @@ use fedimint_ln_common::{
     create_incoming_contract_output, ln_operation, LightningClientContext, LightningCommonGen,
     LightningGateway, LightningModuleTypes, LightningOutput, KIND,
 };
-use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{stream, StreamExt};
 use lightning::routing::gossip::RoutingFees;
 use secp256k1::{KeyPair, PublicKey, Secp256k1};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::info;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 use url::Url;
 
 use self::complete::GatewayCompleteStateMachine;
+use self::db::{RedeemKeyDerivation, RedeemKeyDerivationKey};
 use self::pay::{
     GatewayPayCommon, GatewayPayInvoice, GatewayPayStateMachine, GatewayPayStates,
     OutgoingPaymentError,
 };
 use crate::db::FederationRegistrationKey;
-use crate::gatewaylnrpc::InterceptHtlcRequest;
-use crate::lnrpc_client::ILnRpcClient;
+use crate::gatewaylnrpc::{InterceptHtlcRequest, ProbeRouteRequest};
+use crate::lnrpc_client::{ILnRpcClient, LnRpcHeartbeat, ReconnectBackoff};
 use crate::ng::complete::{GatewayCompleteCommon, GatewayCompleteStates, WaitForPreimageState};
 
 pub const GW_ANNOUNCEMENT_TTL: Duration = Duration::from_secs(600);
 pub const INITIAL_REGISTER_BACKOFF_DURATION: Duration = Duration::from_secs(15);
 
+/// How long to wait for the lightning node to answer a single route probe
+/// before considering the destination unroutable
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum number of route probes that are in flight at the same time
+pub const MAX_CONCURRENT_PROBES: usize = 10;
+/// Amount a probed route needs to be able to carry
+pub const PROBE_AMOUNT: Amount = Amount::from_sats(1);
+/// Maximum number of pay operations that may be in flight at the same time
+/// before the gateway rejects new payments
+pub const DEFAULT_MAX_PENDING_OPERATIONS: usize = 1000;
+/// Smallest invoice amount the gateway pays by default
+pub const DEFAULT_MIN_PAYMENT: Amount = Amount::ZERO;
+/// Largest invoice amount the gateway pays by default
+pub const DEFAULT_MAX_PAYMENT: Amount = Amount::from_msats(u64::MAX);
+/// Largest CLTV delta, including our own `timelock_delta`, the gateway accepts
+/// for an invoice by default
+pub const DEFAULT_MAX_INVOICE_CLTV: u64 = u64::MAX;
+/// Child of the module root secret the gateway's redeem key is derived from
+pub const REDEEM_KEY_CHILD_ID: ChildId = ChildId(0);
+/// How long the list of gateways registered with a federation is cached
+pub const GATEWAY_LIST_CACHE_TTL: Duration = Duration::from_secs(60);
+
 /// The high-level state of a reissue operation started with
 /// [`GatewayClientExt::gateway_pay_bolt11_invoice`].
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -68,7 +96,17 @@ pub enum GatewayExtPayStates {
         preimage: Preimage,
         outpoint: OutPoint,
     },
+    /// The transaction refunding the outgoing contract was submitted but not
+    /// yet accepted by the federation
+    CancelPending {
+        txid: TransactionId,
+        error: OutgoingPaymentError,
+    },
+    /// The federation accepted the transaction refunding the outgoing contract
     Canceled {
+        /// `None` for outcomes recorded before the refund txid was reported
+        #[serde(default)]
+        txid: Option<TransactionId>,
         error: OutgoingPaymentError,
     },
     Fail {
@@ -128,6 +166,9 @@ pub trait GatewayClientExt {
         gateway_id: secp256k1::PublicKey,
     ) -> anyhow::Result<()>;
 
+    /// List all gateways registered with the federation, including this one
+    async fn list_federation_gateways(&self) -> anyhow::Result<Vec<LightningGateway>>;
+
     /// Attempt fulfill HTLC by buying preimage from the federation
     async fn gateway_handle_intercepted_htlc(&self, htlc: Htlc) -> anyhow::Result<OperationId>;
 
@@ -145,7 +186,24 @@ impl GatewayClientExt for Client {
         &self,
         contract_id: ContractId,
     ) -> anyhow::Result<OperationId> {
-        let (_, instance) = self.get_first_module::<GatewayClientModule>(&KIND);
+        let (gateway, instance) = self.get_first_module::<GatewayClientModule>(&KIND);
+
+        // Fail before locking any funds if we already know the payment can't succeed
+        if !gateway.node_connected() {
+            return Err(anyhow::anyhow!(
+                "Lightning node is offline, not accepting payments"
+            ));
+        }
+
+        // Held until the new operation is committed, otherwise concurrent payments
+        // could all see the same spare capacity and exceed the limit together
+        let _pay_admission = gateway.pay_admission.lock().await;
+        let pending_operations = pending_pay_operations(self).await;
+        if pending_operations >= gateway.max_pending_operations {
+            return Err(anyhow::anyhow!(
+                "Gateway is at capacity with {pending_operations} pending payments, try again later"
+            ));
+        }
 
         self.db()
             .autocommit(
@@ -197,42 +255,38 @@ impl GatewayClientExt for Client {
         let (gateway, _instance) = self.get_first_module::<GatewayClientModule>(&KIND);
         let operation = ln_operation(self, operation_id).await?;
 
-        Ok(operation.outcome_or_updates(self.db(), operation_id, || {
-            stream! {
-                yield GatewayExtPayStates::Created;
-
-                let mut stream = gateway.notifier.subscribe(operation_id).await;
-                loop {
-                    if let Some(GatewayClientStateMachines::Pay(state)) = stream.next().await {
-                        match state.state {
-                            GatewayPayStates::Preimage(outpoint, preimage) => {
-                                yield GatewayExtPayStates::Preimage{ preimage: preimage.clone() };
-
-                                if self.await_primary_module_output(operation_id, outpoint).await.is_ok() {
-                                    yield GatewayExtPayStates::Success{ preimage: preimage.clone(), outpoint };
-                                    return;
-                                }
-                            }
-                            GatewayPayStates::Canceled { txid, contract_id: _, error } => {
-                                if self.transaction_updates(operation_id).await.await_tx_accepted(txid).await.is_ok() {
-                                    yield GatewayExtPayStates::Canceled{ error };
-                                    return;
-                                }
-
-                                yield GatewayExtPayStates::Fail { error, error_message: "Refund transaction was not accepted by the federation".to_string() };
-                            }
-                            GatewayPayStates::OfferDoesNotExist(contract_id) => {
-                                yield GatewayExtPayStates::OfferDoesNotExist { contract_id };
-                            }
-                            GatewayPayStates::Failed{ error, error_message } => {
-                                yield GatewayExtPayStates::Fail{ error, error_message };
-                            },
-                            _ => {}
+        Ok(
+            operation.outcome_or_updates(self.db(), operation_id, move || {
+                let states = stream::once(gateway.notifier.subscribe(operation_id))
+                    .flatten()
+                    .filter_map(|state| async move {
+                        match state {
+                            GatewayClientStateMachines::Pay(state) => Some(state.state),
+                            _ => None,
                         }
-                    }
-                }
-            }
-        }))
+                    });
+
+                pay_state_updates(
+                    Box::pin(states),
+                    move |outpoint| {
+                        Box::pin(async move {
+                            self.await_primary_module_output(operation_id, outpoint)
+                                .await
+                                .is_ok()
+                        })
+                    },
+                    move |txid| {
+                        Box::pin(async move {
+                            self.transaction_updates(operation_id)
+                                .await
+                                .await_tx_accepted(txid)
+                                .await
+                                .map_err(|e| e.to_string())
+                        })
+                    },
+                )
+            }),
+        )
     }
 
     /// Register this gateway with the federation
@@ -260,6 +314,11 @@ impl GatewayClientExt for Client {
         Ok(())
     }
 
+    async fn list_federation_gateways(&self) -> anyhow::Result<Vec<LightningGateway>> {
+        let (gateway, _) = self.get_first_module::<GatewayClientModule>(&KIND);
+        gateway.list_federation_gateways().await
+    }
+
     /// Handles an intercepted HTLC by buying a preimage from the federation
     async fn gateway_handle_intercepted_htlc(&self, htlc: Htlc) -> anyhow::Result<OperationId> {
         let (gateway, instance) = self.get_first_module::<GatewayClientModule>(&KIND);
@@ -307,13 +366,146 @@ impl GatewayClientExt for Client {
     }
 }
 
+/// Checks that `preimage` is the proof of payment for an invoice with the given
+/// payment hash
+pub fn verify_preimage(preimage: &Preimage, payment_hash: &sha256::Hash) -> bool {
+    sha256::Hash::hash(&preimage.0) == *payment_hash
+}
+
+/// Translates the states of a pay state machine into the updates returned by
+/// [`GatewayClientExt::gateway_subscribe_ln_pay`]. `output_accepted` reports
+/// whether the federation accepted the transaction claiming the outgoing
+/// contract, `refund_accepted` why it didn't accept the one refunding it.
+fn pay_state_updates<'a>(
+    mut states: BoxStream<'a, GatewayPayStates>,
+    output_accepted: impl Fn(OutPoint) -> BoxFuture<'a, bool> + Send + 'a,
+    refund_accepted: impl Fn(TransactionId) -> BoxFuture<'a, Result<(), String>> + Send + 'a,
+) -> BoxStream<'a, GatewayExtPayStates> {
+    Box::pin(stream! {
+        yield GatewayExtPayStates::Created;
+
+        while let Some(state) = states.next().await {
+            match state {
+                GatewayPayStates::Preimage(outpoint, preimage) => {
+                    yield GatewayExtPayStates::Preimage{ preimage: preimage.clone() };
+
+                    if output_accepted(outpoint).await {
+                        yield GatewayExtPayStates::Success{ preimage, outpoint };
+                        return;
+                    }
+                }
+                GatewayPayStates::Canceled { txid, contract_id: _, error } => {
+                    yield GatewayExtPayStates::CancelPending { txid, error: error.clone() };
+
+                    match refund_accepted(txid).await {
+                        Ok(()) => {
+                            yield GatewayExtPayStates::Canceled { txid: Some(txid), error };
+                            return;
+                        }
+                        Err(e) => {
+                            yield GatewayExtPayStates::Fail { error, error_message: format!("Refund transaction was not accepted by the federation: {e}") };
+                        }
+                    }
+                }
+                GatewayPayStates::OfferDoesNotExist(contract_id) => {
+                    yield GatewayExtPayStates::OfferDoesNotExist { contract_id };
+                }
+                GatewayPayStates::Failed{ error, error_message } => {
+                    yield GatewayExtPayStates::Fail{ error, error_message };
+                },
+                _ => {}
+            }
+        }
+    })
+}
+
+/// Final outcome of a pay operation simulated by [`simulate_pay_flow`]
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Debug, Clone)]
+pub enum SimulatedOutcome {
+    Success {
+        preimage: Preimage,
+        outpoint: OutPoint,
+    },
+    Canceled {
+        contract_id: ContractId,
+        error: OutgoingPaymentError,
+    },
+    OfferDoesNotExist {
+        contract_id: ContractId,
+    },
+    Fail {
+        error: OutgoingPaymentError,
+        error_message: String,
+    },
+}
+
+/// **Only intended for tests**: produces the updates
+/// [`GatewayClientExt::gateway_subscribe_ln_pay`] would return for a payment
+/// ending in `outcome`, without needing a federation or lightning node. The
+/// updates are generated by the same code as for real payments and the
+/// federation is assumed to accept all transactions.
+#[cfg(any(test, feature = "test-utils"))]
+pub fn simulate_pay_flow(
+    outcome: SimulatedOutcome,
+) -> UpdateStreamOrOutcome<'static, GatewayExtPayStates> {
+    let state = match outcome {
+        SimulatedOutcome::Success { preimage, outpoint } => {
+            GatewayPayStates::Preimage(outpoint, preimage)
+        }
+        SimulatedOutcome::Canceled { contract_id, error } => GatewayPayStates::Canceled {
+            txid: TransactionId::all_zeros(),
+            contract_id,
+            error,
+        },
+        SimulatedOutcome::OfferDoesNotExist { contract_id } => {
+            GatewayPayStates::OfferDoesNotExist(contract_id)
+        }
+        SimulatedOutcome::Fail {
+            error,
+            error_message,
+        } => GatewayPayStates::Failed {
+            error,
+            error_message,
+        },
+    };
+
+    UpdateStreamOrOutcome::UpdateStream(pay_state_updates(
+        Box::pin(stream::once(async move { state })),
+        |_| Box::pin(async { true }),
+        |_| Box::pin(async { Ok(()) }),
+    ))
+}
+
+/// Counts the pay operations whose state machines haven't reached a final state
+/// yet
+async fn pending_pay_operations(client: &Client) -> usize {
+    let mut pending = 0;
+    for operation_id in client.get_active_operations().await {
+        if let Some(operation) = client.operation_log().get_operation(operation_id).await {
+            if operation.operation_type() == KIND.as_str()
+                && matches!(operation.meta::<GatewayMeta>(), GatewayMeta::Pay)
+            {
+                pending += 1;
+            }
+        }
+    }
+    pending
+}
+
 #[derive(Debug, Clone)]
 pub struct GatewayClientGen {
     pub lnrpc: Arc<dyn ILnRpcClient>,
+    pub heartbeat: Arc<LnRpcHeartbeat>,
     pub node_pub_key: secp256k1::PublicKey,
     pub timelock_delta: u64,
     pub mint_channel_id: u64,
     pub fees: RoutingFees,
+    pub max_pending_operations: usize,
+    pub min_payment: Amount,
+    pub max_payment: Amount,
+    pub max_invoice_cltv: u64,
+    pub reconnect_backoff: ReconnectBackoff,
 }
 
 impl ExtendsCommonModuleGen for GatewayClientGen {
@@ -332,25 +524,41 @@ impl ClientModuleGen for GatewayClientGen {
     async fn init(
         &self,
         cfg: LightningClientConfig,
-        _db: Database,
+        db: Database,
         _api_version: ApiVersion,
         module_root_secret: DerivableSecret,
         notifier: ModuleNotifier<DynGlobalClientContext, <Self::Module as ClientModule>::States>,
         _api: DynGlobalApi,
         module_api: DynModuleApi,
     ) -> anyhow::Result<Self::Module> {
+        let redeem_key = module_root_secret
+            .child_key(REDEEM_KEY_CHILD_ID)
+            .to_secp_key(&Secp256k1::new());
+        let derivation = RedeemKeyDerivation {
+            child_id: REDEEM_KEY_CHILD_ID.0,
+            redeem_pub_key: redeem_key.public_key(),
+        };
+
+        check_redeem_key_derivation(&db, &derivation).await?;
+
         Ok(GatewayClientModule {
             lnrpc: self.lnrpc.clone(),
+            heartbeat: self.heartbeat.clone(),
             cfg,
             notifier,
-            redeem_key: module_root_secret
-                .child_key(ChildId(0))
-                .to_secp_key(&Secp256k1::new()),
+            redeem_key,
             node_pub_key: self.node_pub_key,
             timelock_delta: self.timelock_delta,
             mint_channel_id: self.mint_channel_id,
             fees: self.fees,
+            max_pending_operations: self.max_pending_operations,
+            min_payment: self.min_payment,
+            max_payment: self.max_payment,
+            max_invoice_cltv: self.max_invoice_cltv,
+            reconnect_backoff: self.reconnect_backoff,
             module_api,
+            gateway_list_cache: Mutex::new(None),
+            pay_admission: Mutex::new(()),
         })
     }
 }
@@ -360,6 +568,10 @@ pub struct GatewayClientContext {
     lnrpc: Arc<dyn ILnRpcClient>,
     redeem_key: bitcoin::KeyPair,
     timelock_delta: u64,
+    min_payment: Amount,
+    max_payment: Amount,
+    max_invoice_cltv: u64,
+    reconnect_backoff: ReconnectBackoff,
     secp: secp256k1_zkp::Secp256k1<secp256k1_zkp::All>,
     pub ln_decoder: Decoder,
     notifier: ModuleNotifier<DynGlobalClientContext, GatewayClientStateMachines>,
@@ -379,6 +591,7 @@ impl From<&GatewayClientContext> for LightningClientContext {
 #[derive(Debug)]
 pub struct GatewayClientModule {
     lnrpc: Arc<dyn ILnRpcClient>,
+    heartbeat: Arc<LnRpcHeartbeat>,
     cfg: LightningClientConfig,
     pub notifier: ModuleNotifier<DynGlobalClientContext, GatewayClientStateMachines>,
     pub redeem_key: KeyPair,
@@ -386,7 +599,16 @@ pub struct GatewayClientModule {
     timelock_delta: u64,
     mint_channel_id: u64,
     fees: RoutingFees,
+    max_pending_operations: usize,
+    min_payment: Amount,
+    max_payment: Amount,
+    max_invoice_cltv: u64,
+    reconnect_backoff: ReconnectBackoff,
     module_api: DynModuleApi,
+    gateway_list_cache: Mutex<Option<(SystemTime, Vec<LightningGateway>)>>,
+    /// Serializes checking the number of pending payments and starting a new
+    /// one
+    pay_admission: Mutex<()>,
 }
 
 impl ClientModule for GatewayClientModule {
@@ -399,6 +621,10 @@ impl ClientModule for GatewayClientModule {
             lnrpc: self.lnrpc.clone(),
             redeem_key: self.redeem_key,
             timelock_delta: self.timelock_delta,
+            min_payment: self.min_payment,
+            max_payment: self.max_payment,
+            max_invoice_cltv: self.max_invoice_cltv,
+            reconnect_backoff: self.reconnect_backoff,
             secp: secp256k1_zkp::Secp256k1::new(),
             ln_decoder: self.decoder(),
             notifier: self.notifier.clone(),
@@ -454,6 +680,65 @@ impl GatewayClientModule {
         }
     }
 
+    /// Fetches the gateways registered with the federation, answering from
+    /// the cache if it is younger than [`GATEWAY_LIST_CACHE_TTL`]
+    async fn list_federation_gateways(&self) -> anyhow::Result<Vec<LightningGateway>> {
+        let mut cache = self.gateway_list_cache.lock().await;
+        if let Some((fetched_at, gateways)) = cache.as_ref() {
+            if fedimint_core::time::now() < *fetched_at + GATEWAY_LIST_CACHE_TTL {
+                return Ok(gateways.clone());
+            }
+        }
+
+        let gateways = self.module_api.fetch_gateways().await?;
+        *cache = Some((fedimint_core::time::now(), gateways.clone()));
+        Ok(gateways)
+    }
+
+    /// Whether the lightning node answered its recent liveness checks
+    pub fn node_connected(&self) -> bool {
+        self.heartbeat.node_connected()
+    }
+
+    /// Checks whether the lightning node can route to each of the given
+    /// destinations without making any payments. Probes that fail or time out
+    /// are reported as unroutable.
+    pub async fn probe_destinations(&self, dests: Vec<PublicKey>) -> Vec<(PublicKey, bool)> {
+        self.probe_destinations_with_timeout(dests, PROBE_TIMEOUT)
+            .await
+    }
+
+    /// Like [`Self::probe_destinations`], but gives up on a single probe after
+    /// `probe_timeout` instead of [`PROBE_TIMEOUT`]
+    pub async fn probe_destinations_with_timeout(
+        &self,
+        dests: Vec<PublicKey>,
+        probe_timeout: Duration,
+    ) -> Vec<(PublicKey, bool)> {
+        stream::iter(dests)
+            .map(|dest| async move {
+                let probe = self.lnrpc.probe(ProbeRouteRequest {
+                    node_pub_key: dest.serialize().to_vec(),
+                    amount_msat: PROBE_AMOUNT.msats,
+                });
+                let routable = match timeout(probe_timeout, probe).await {
+                    Ok(Ok(response)) => response.routable,
+                    Ok(Err(e)) => {
+                        warn!("Failed to probe route to {dest}: {e:?}");
+                        false
+                    }
+                    Err(_) => {
+                        warn!("Timed out probing route to {dest}");
+                        false
+                    }
+                };
+                (dest, routable)
+            })
+            .buffered(MAX_CONCURRENT_PROBES)
+            .collect()
+            .await
+    }
+
     async fn register_with_federation(
         &self,
         dbtx: &mut DatabaseTransaction<'_>,
@@ -571,6 +856,45 @@ impl State for GatewayClientStateMachines {
     }
 }
 
+/// Returned when the module is initialized with a secret that derives a
+/// different redeem key than the one it was first initialized with
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Derived redeem key {derived} does not match the persisted redeem key {persisted}, was the gateway restored from the wrong secret?")]
+pub struct RedeemKeyMismatch {
+    pub derived: PublicKey,
+    pub persisted: PublicKey,
+}
+
+/// Persists the redeem key derivation on first use and afterwards refuses
+/// derivations that differ from it, since a different redeem key would leave
+/// us unable to claim contracts funded to the old one
+pub async fn check_redeem_key_derivation(
+    db: &Database,
+    derivation: &RedeemKeyDerivation,
+) -> Result<(), RedeemKeyMismatch> {
+    let mut dbtx = db.begin_transaction().await;
+    match dbtx.get_value(&RedeemKeyDerivationKey).await {
+        Some(persisted) if persisted != *derivation => {
+            error!(
+                ?persisted,
+                derived = ?derivation,
+                "Derived redeem key does not match the persisted one"
+            );
+            Err(RedeemKeyMismatch {
+                derived: derivation.redeem_pub_key,
+                persisted: persisted.redeem_pub_key,
+            })
+        }
+        Some(_) => Ok(()),
+        None => {
+            dbtx.insert_new_entry(&RedeemKeyDerivationKey, derivation)
+                .await;
+            dbtx.commit_tx().await;
+            Ok(())
+        }
+    }
+}
+
 #[derive(Error, Debug, Serialize, Deserialize, Encodable, Decodable, Clone, Eq, PartialEq)]
 pub enum ReceiveError {
     #[error("Route htlc error")]
@@ -609,3 +933,104 @@ impl TryFrom<InterceptHtlcRequest> for Htlc {
         })
     }
 }
+
+#[cfg(test)]
+mod pay_flow_tests {
+    use bitcoin_hashes::Hash;
+    use fedimint_core::{OutPoint, TransactionId};
+    use fedimint_ln_client::contracts::ContractId;
+    use fedimint_ln_common::contracts::Preimage;
+    use futures::StreamExt;
+
+    use super::{simulate_pay_flow, GatewayExtPayStates, SimulatedOutcome};
+    use crate::ng::pay::OutgoingPaymentError;
+
+    #[tokio::test]
+    async fn simulated_pay_flow_ends_in_outcome() {
+        let preimage = Preimage([1; 32]);
+        let outpoint = OutPoint {
+            txid: TransactionId::all_zeros(),
+            out_idx: 0,
+        };
+
+        let updates = simulate_pay_flow(SimulatedOutcome::Success {
+            preimage: preimage.clone(),
+            outpoint,
+        })
+        .into_stream()
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(
+            updates,
+            vec![
+                GatewayExtPayStates::Created,
+                GatewayExtPayStates::Preimage {
+                    preimage: preimage.clone()
+                },
+                GatewayExtPayStates::Success { preimage, outpoint },
+            ]
+        );
+
+        let contract_id = ContractId::all_zeros();
+        let updates = simulate_pay_flow(SimulatedOutcome::OfferDoesNotExist { contract_id })
+            .into_stream()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            updates,
+            vec![
+                GatewayExtPayStates::Created,
+                GatewayExtPayStates::OfferDoesNotExist { contract_id },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_cancel_reports_pending_refund_first() {
+        let contract_id = ContractId::all_zeros();
+        let error = OutgoingPaymentError::OutgoingContractDoesNotExist { contract_id };
+
+        let updates = simulate_pay_flow(SimulatedOutcome::Canceled {
+            contract_id,
+            error: error.clone(),
+        })
+        .into_stream()
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(
+            updates,
+            vec![
+                GatewayExtPayStates::Created,
+                GatewayExtPayStates::CancelPending {
+                    txid: TransactionId::all_zeros(),
+                    error: error.clone(),
+                },
+                GatewayExtPayStates::Canceled {
+                    txid: Some(TransactionId::all_zeros()),
+                    error,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn canceled_without_txid_deserializes() {
+        let contract_id = ContractId::all_zeros();
+        let error = OutgoingPaymentError::OutgoingContractDoesNotExist { contract_id };
+        let mut json = serde_json::to_value(GatewayExtPayStates::Canceled {
+            txid: Some(TransactionId::all_zeros()),
+            error: error.clone(),
+        })
+        .unwrap();
+        json["Canceled"]
+            .as_object_mut()
+            .unwrap()
+            .remove("txid")
+            .unwrap();
+
+        assert_eq!(
+            serde_json::from_value::<GatewayExtPayStates>(json).unwrap(),
+            GatewayExtPayStates::Canceled { txid: None, error }
+        );
+    }
+}
@@ -15,9 +15,9 @@ use futures::future;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use super::{GatewayClientContext, GatewayClientStateMachines};
+use super::{verify_preimage, GatewayClientContext, GatewayClientStateMachines};
 use crate::gatewaylnrpc::{PayInvoiceRequest, PayInvoiceResponse};
-use crate::lnrpc_client::LightningRpcError;
+use crate::lnrpc_client::{call_with_reconnect, LightningRpcError};
 
 #[cfg_attr(doc, aquamarine::aquamarine)]
 /// State machine that executes the Lightning payment on behalf of
@@ -121,6 +121,12 @@ pub enum OutgoingContractError {
     MissingContractData,
     #[error("The invoice is expired. Expiry duration: {0:?}")]
     InvoiceExpired(Duration),
+    #[error("Invoice amount {0} is below the gateway's minimum payment of {1}")]
+    AmountBelowMinimum(Amount, Amount),
+    #[error("Invoice amount {0} is above the gateway's maximum payment of {1}")]
+    AmountAboveMaximum(Amount, Amount),
+    #[error("Invoice requires a CLTV delta of {0} blocks, the gateway accepts at most {1}")]
+    InvoiceCltvTooHigh(u64, u64),
 }
 
 #[derive(Error, Debug, Serialize, Deserialize, Encodable, Decodable, Clone, Eq, PartialEq)]
@@ -201,6 +207,9 @@ impl GatewayPayInvoice {
                 context.redeem_key,
                 context.timelock_delta,
                 consensus_block_height.unwrap(),
+                context.min_payment,
+                context.max_payment,
+                context.max_invoice_cltv,
             )
             .await
             .map_err(|e| OutgoingPaymentError::InvalidOutgoingContract {
@@ -222,19 +231,30 @@ impl GatewayPayInvoice {
         let invoice = buy_preimage.invoice.clone();
         let max_delay = buy_preimage.max_delay;
         let max_fee_msat = buy_preimage.max_send_amount.msats;
-        match context
-            .lnrpc
-            .pay(PayInvoiceRequest {
-                invoice: invoice.to_string(),
-                max_delay,
-                max_fee_msat,
-                payment_hash: invoice.payment_hash().to_vec(),
-            })
-            .await
+        let request = PayInvoiceRequest {
+            invoice: invoice.to_string(),
+            max_delay,
+            max_fee_msat,
+            payment_hash: invoice.payment_hash().to_vec(),
+        };
+        match call_with_reconnect(context.lnrpc.as_ref(), &context.reconnect_backoff, || {
+            context.lnrpc.pay(request.clone())
+        })
+        .await
         {
             Ok(PayInvoiceResponse { preimage, .. }) => {
                 let slice: [u8; 32] = preimage.try_into().expect("Failed to parse preimage");
-                Ok(Preimage(slice))
+                let preimage = Preimage(slice);
+                if !verify_preimage(&preimage, invoice.payment_hash()) {
+                    return Err(OutgoingPaymentError::LightningPayError {
+                        contract,
+                        lightning_error: LightningRpcError::FailedPayment {
+                            failure_reason: "Preimage does not match the invoice's payment hash"
+                                .to_string(),
+                        },
+                    });
+                }
+                Ok(preimage)
             }
             Err(error) => Err(OutgoingPaymentError::LightningPayError {
                 contract,
@@ -306,6 +326,9 @@ impl GatewayPayInvoice {
         redeem_key: bitcoin::KeyPair,
         timelock_delta: u64,
         consensus_block_height: u64,
+        min_payment: Amount,
+        max_payment: Amount,
+        max_invoice_cltv: u64,
     ) -> Result<PaymentParameters, OutgoingContractError> {
         let our_pub_key = secp256k1::XOnlyPublicKey::from_keypair(&redeem_key).0;
 
@@ -324,6 +347,9 @@ impl GatewayPayInvoice {
                 .ok_or(OutgoingContractError::InvoiceMissingAmount)?,
         );
 
+        validate_payment_amount(invoice_amount, min_payment, max_payment)?;
+        validate_invoice_cltv(&invoice, timelock_delta, max_invoice_cltv)?;
+
         if account.amount < invoice_amount {
             return Err(OutgoingContractError::Underfunded(
                 invoice_amount,
@@ -350,6 +376,52 @@ impl GatewayPayInvoice {
     }
 }
 
+/// Checks that the gateway is willing to pay an invoice of `invoice_amount`,
+/// both limits are inclusive
+fn validate_payment_amount(
+    invoice_amount: Amount,
+    min_payment: Amount,
+    max_payment: Amount,
+) -> Result<(), OutgoingContractError> {
+    if invoice_amount < min_payment {
+        return Err(OutgoingContractError::AmountBelowMinimum(
+            invoice_amount,
+            min_payment,
+        ));
+    }
+
+    if invoice_amount > max_payment {
+        return Err(OutgoingContractError::AmountAboveMaximum(
+            invoice_amount,
+            max_payment,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that paying `invoice` doesn't lock up the gateway's funds for longer
+/// than `max_invoice_cltv` blocks, counting the invoice's final CLTV delta and
+/// our own `timelock_delta`
+fn validate_invoice_cltv(
+    invoice: &lightning_invoice::Invoice,
+    timelock_delta: u64,
+    max_invoice_cltv: u64,
+) -> Result<(), OutgoingContractError> {
+    let invoice_cltv = invoice
+        .min_final_cltv_expiry()
+        .saturating_add(timelock_delta);
+
+    if invoice_cltv > max_invoice_cltv {
+        return Err(OutgoingContractError::InvoiceCltvTooHigh(
+            invoice_cltv,
+            max_invoice_cltv,
+        ));
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Decodable, Encodable, Serialize, Deserialize)]
 pub struct PaymentParameters {
     max_delay: u64,
@@ -482,3 +554,54 @@ impl GatewayPayCancelContract {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin_hashes::{sha256, Hash};
+    use fedimint_core::Amount;
+    use lightning::ln::PaymentSecret;
+    use lightning_invoice::{Currency, InvoiceBuilder};
+
+    use super::{validate_invoice_cltv, validate_payment_amount, OutgoingContractError};
+
+    #[test]
+    fn test_validate_payment_amount() {
+        let min = Amount::from_msats(1_000);
+        let max = Amount::from_msats(10_000);
+
+        assert!(validate_payment_amount(min, min, max).is_ok());
+        assert!(validate_payment_amount(max, min, max).is_ok());
+        assert_matches!(
+            validate_payment_amount(Amount::from_msats(999), min, max),
+            Err(OutgoingContractError::AmountBelowMinimum(_, _))
+        );
+        assert_matches!(
+            validate_payment_amount(Amount::from_msats(10_001), min, max),
+            Err(OutgoingContractError::AmountAboveMaximum(_, _))
+        );
+    }
+
+    #[test]
+    fn test_validate_invoice_cltv() {
+        let ctx = bitcoin::secp256k1::Secp256k1::new();
+        let node_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let invoice = InvoiceBuilder::new(Currency::Regtest)
+            .description("".to_string())
+            .payment_hash(sha256::Hash::hash(&[0; 32]))
+            .current_timestamp()
+            .min_final_cltv_expiry(144)
+            .payment_secret(PaymentSecret([0; 32]))
+            .amount_milli_satoshis(1000)
+            .build_signed(|m| ctx.sign_ecdsa_recoverable(m, &node_key))
+            .unwrap();
+
+        // the invoice's 144 blocks plus our own timelock delta of 10
+        assert!(validate_invoice_cltv(&invoice, 10, 154).is_ok());
+        assert_matches!(
+            validate_invoice_cltv(&invoice, 10, 153),
+            Err(OutgoingContractError::InvoiceCltvTooHigh(154, 153))
+        );
+    }
+}
@@ -1,13 +1,20 @@
+use std::collections::HashSet;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use assert_matches::assert_matches;
 use bitcoin_hashes::{sha256, Hash};
+use fedimint_client::db::ClientSecretKey;
+use fedimint_client::derivable_secret::DerivableSecret;
+use fedimint_client::secret::{PlainRootSecretStrategy, RootSecretStrategy};
 use fedimint_client::sm::OperationId;
 use fedimint_client::transaction::{ClientInput, ClientOutput, TransactionBuilder};
-use fedimint_client::Client;
+use fedimint_client::{Client, ClientSecret};
 use fedimint_core::core::IntoDynInstance;
+use fedimint_core::db::mem_impl::MemDatabase;
+use fedimint_core::db::Database;
+use fedimint_core::module::registry::ModuleDecoderRegistry;
 use fedimint_core::task::sleep;
 use fedimint_core::util::NextOrPending;
 use fedimint_core::{sats, Amount, OutPoint, TransactionId};
@@ -28,12 +35,18 @@ use fedimint_ln_server::LightningGen;
 use fedimint_testing::federation::FederationTest;
 use fedimint_testing::fixtures::Fixtures;
 use fedimint_testing::gateway::GatewayTest;
+use fedimint_testing::ln::mock::FakeLightningTest;
 use fedimint_testing::ln::LightningTest;
 use futures::Future;
+use ln_gateway::lnrpc_client::ILnRpcClient;
+use ln_gateway::ng::db::RedeemKeyDerivation;
 use ln_gateway::ng::{
-    GatewayClientExt, GatewayClientModule, GatewayClientStateMachines, GatewayExtPayStates,
-    GatewayExtReceiveStates, GatewayMeta, Htlc, GW_ANNOUNCEMENT_TTL,
+    check_redeem_key_derivation, verify_preimage, GatewayClientExt, GatewayClientModule,
+    GatewayClientStateMachines, GatewayExtPayStates, GatewayExtReceiveStates, GatewayMeta, Htlc,
+    RedeemKeyMismatch, GW_ANNOUNCEMENT_TTL, MAX_CONCURRENT_PROBES, REDEEM_KEY_CHILD_ID,
 };
+use ln_gateway::rpc::ConnectFedPayload;
+use secp256k1::Secp256k1;
 use url::Url;
 
 fn fixtures() -> Fixtures {
@@ -217,6 +230,10 @@ async fn test_gateway_client_pay_invalid_invoice() -> anyhow::Result<()> {
                         .await?
                         .into_stream();
                     assert_eq!(gw_pay_sub.ok().await?, GatewayExtPayStates::Created);
+                    assert_matches!(
+                        gw_pay_sub.ok().await?,
+                        GatewayExtPayStates::CancelPending { .. }
+                    );
                     assert_matches!(gw_pay_sub.ok().await?, GatewayExtPayStates::Canceled { .. });
 
                     // Assert that the user receives a refund
@@ -514,6 +531,10 @@ async fn test_gateway_cannot_pay_expired_invoice() -> anyhow::Result<()> {
                         .await?
                         .into_stream();
                     assert_eq!(gw_pay_sub.ok().await?, GatewayExtPayStates::Created);
+                    assert_matches!(
+                        gw_pay_sub.ok().await?,
+                        GatewayExtPayStates::CancelPending { .. }
+                    );
                     assert_matches!(gw_pay_sub.ok().await?, GatewayExtPayStates::Canceled { .. });
 
                     assert_matches!(pay_sub.ok().await?, LnPayState::WaitingForRefund { .. });
@@ -532,3 +553,188 @@ async fn test_gateway_cannot_pay_expired_invoice() -> anyhow::Result<()> {
     )
     .await
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_gateway_probe_destinations() -> anyhow::Result<()> {
+    gateway_test(|gateway, other_lightning_client, fed, _| async move {
+        let gateway = gateway.remove_client(&fed).await;
+        let (gateway_module, _) =
+            gateway.get_first_module::<GatewayClientModule>(&fedimint_ln_client::KIND);
+
+        let other_node_pub_key =
+            secp256k1::PublicKey::from_slice(&other_lightning_client.info().await?.pub_key)?;
+        let probes = gateway_module
+            .probe_destinations(vec![other_node_pub_key])
+            .await;
+        assert_eq!(probes, vec![(other_node_pub_key, true)]);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_gateway_probe_destinations_reports_failed_probes_as_unroutable() -> anyhow::Result<()>
+{
+    let fixtures = fixtures();
+    let fed = fixtures.new_fed().await;
+    // Only the fake node can fail or hold probes to chosen destinations
+    let lightning = FakeLightningTest::new();
+    let mut gateway = fixtures.new_gateway(Box::new(lightning.clone())).await;
+    gateway.connect_fed(&fed).await;
+    let gateway = gateway.remove_client(&fed).await;
+    let (gateway_module, _) =
+        gateway.get_first_module::<GatewayClientModule>(&fedimint_ln_client::KIND);
+
+    let routable = lightning.gateway_node_pub_key;
+    let error = FakeLightningTest::probe_error_node();
+    let hold = FakeLightningTest::probe_hold_node();
+    let probes = gateway_module
+        .probe_destinations_with_timeout(vec![routable, error, hold], Duration::from_secs(1))
+        .await;
+    assert_eq!(
+        probes,
+        vec![(routable, true), (error, false), (hold, false)]
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_gateway_probe_destinations_bounds_concurrency() -> anyhow::Result<()> {
+    let fixtures = fixtures();
+    let fed = fixtures.new_fed().await;
+    let lightning = FakeLightningTest::new();
+    let mut gateway = fixtures.new_gateway(Box::new(lightning.clone())).await;
+    gateway.connect_fed(&fed).await;
+    let gateway = gateway.remove_client(&fed).await;
+    let (gateway_module, _) =
+        gateway.get_first_module::<GatewayClientModule>(&fedimint_ln_client::KIND);
+
+    let secp = secp256k1::Secp256k1::new();
+    let dests = (1..=(3 * MAX_CONCURRENT_PROBES as u8))
+        .map(|i| {
+            let secret_key = secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+            secp256k1::PublicKey::from_secret_key(&secp, &secret_key)
+        })
+        .collect::<Vec<_>>();
+    let probes = gateway_module.probe_destinations(dests.clone()).await;
+    assert_eq!(
+        probes,
+        dests
+            .into_iter()
+            .map(|dest| (dest, true))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(lightning.max_probes_in_flight(), MAX_CONCURRENT_PROBES);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_gateway_rejects_payments_at_capacity() -> anyhow::Result<()> {
+    let fixtures = fixtures();
+    let fed = fixtures.new_fed().await;
+    let user_client = fed.new_client().await;
+    // Only the fake node can keep a payment in flight indefinitely
+    let lightning = FakeLightningTest::new();
+    let mut gateway = fixtures
+        .new_gateway_with_max_pending_operations(Box::new(lightning.clone()), 1)
+        .await;
+    gateway.connect_fed(&fed).await;
+    let gateway = gateway.remove_client(&fed).await;
+
+    // Print money for user_client
+    let (_, outpoint) = user_client.print_money(sats(1000)).await?;
+    user_client.receive_money(outpoint).await?;
+
+    // The first payment never completes and takes up the only slot
+    let held_invoice = lightning.hold_invoice(sats(250));
+    let (_, held_contract_id) = user_client.pay_bolt11_invoice(held_invoice).await?;
+    let held_operation_id = gateway.gateway_pay_bolt11_invoice(held_contract_id).await?;
+    assert_eq!(
+        gateway.get_active_operations().await,
+        HashSet::from([held_operation_id])
+    );
+
+    // So the gateway must not even start paying the second one
+    let invoice = lightning.invoice(sats(250), None).await?;
+    let (_, contract_id) = user_client.pay_bolt11_invoice(invoice).await?;
+    assert!(gateway
+        .gateway_pay_bolt11_invoice(contract_id)
+        .await
+        .is_err());
+    assert_eq!(
+        gateway.get_active_operations().await,
+        HashSet::from([held_operation_id])
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_gateway_client_rejects_different_redeem_key() -> anyhow::Result<()> {
+    let fixtures = fixtures();
+    let fed = fixtures.new_fed().await;
+    let mut gateway = fixtures.new_gateway(fixtures.lnd().await).await;
+    gateway.connect_fed(&fed).await;
+    let rpc = gateway.get_rpc().await;
+    let connect = ConnectFedPayload {
+        connect: fed.connection_code().to_string(),
+    };
+
+    // Reconnecting re-initializes the module on the same database
+    assert!(rpc.connect_federation(connect.clone()).await.is_ok());
+
+    // As if the gateway was restored from another secret
+    let client = gateway.select_client(&fed).await;
+    let mut dbtx = client.db().begin_transaction().await;
+    dbtx.insert_entry(
+        &ClientSecretKey::<PlainRootSecretStrategy>::default(),
+        &ClientSecret::new(PlainRootSecretStrategy::random(&mut rand::thread_rng())),
+    )
+    .await;
+    dbtx.commit_tx().await;
+
+    assert!(rpc.connect_federation(connect).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_redeem_key_derivation_rejects_different_redeem_key() {
+    let db = Database::new(MemDatabase::new(), ModuleDecoderRegistry::default());
+    let secp = Secp256k1::new();
+    let derivation = |secret: DerivableSecret| RedeemKeyDerivation {
+        child_id: REDEEM_KEY_CHILD_ID.0,
+        redeem_pub_key: secret
+            .child_key(REDEEM_KEY_CHILD_ID)
+            .to_secp_key(&secp)
+            .public_key(),
+    };
+    let persisted = derivation(PlainRootSecretStrategy::to_root_secret(
+        &PlainRootSecretStrategy::random(&mut rand::thread_rng()),
+    ));
+    let restored = derivation(PlainRootSecretStrategy::to_root_secret(
+        &PlainRootSecretStrategy::random(&mut rand::thread_rng()),
+    ));
+
+    assert_eq!(check_redeem_key_derivation(&db, &persisted).await, Ok(()));
+    assert_eq!(check_redeem_key_derivation(&db, &persisted).await, Ok(()));
+    assert_eq!(
+        check_redeem_key_derivation(&db, &restored).await,
+        Err(RedeemKeyMismatch {
+            derived: restored.redeem_pub_key,
+            persisted: persisted.redeem_pub_key,
+        })
+    );
+}
+
+#[test]
+fn test_verify_preimage() {
+    let preimage = Preimage(rand::random());
+    assert!(verify_preimage(&preimage, &sha256(&preimage.0)));
+
+    let other_preimage = Preimage(rand::random());
+    assert!(!verify_preimage(&other_preimage, &sha256(&preimage.0)));
+}
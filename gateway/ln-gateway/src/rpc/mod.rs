@@ -17,6 +17,7 @@ use lightning::routing::gossip::RoutingFees;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::sync::oneshot;
 
+use crate::utils::CircuitBreakerState;
 use crate::{Gateway, Result};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -61,6 +62,9 @@ pub struct FederationInfo {
     /// Unique identifier of the fed
     pub federation_id: FederationId,
     pub balance_msat: Amount,
+    /// Whether the gateway currently routes payments for the fed
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerState,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
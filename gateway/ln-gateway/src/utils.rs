@@ -1,9 +1,11 @@
 use std::future::Future;
 use std::result::Result;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use fedimint_core::task;
-use tracing::info;
+use fedimint_core::time::now;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
 /// Run the supplied closure `op_fn` up to `max_attempts` times. Wait for the
 /// supplied `Duration` `interval` between attempts
@@ -44,6 +46,77 @@ where
     }
 }
 
+/// Whether a [`CircuitBreaker`] currently lets requests through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitBreakerState {
+    /// Requests are accepted normally
+    #[default]
+    Closed,
+    /// Too many consecutive failures, requests are rejected until the
+    /// cooldown has passed
+    Open,
+    /// The cooldown has passed, requests are accepted again but a single
+    /// failure opens the breaker again
+    HalfOpen,
+}
+
+/// Stops accepting requests after `max_failures` consecutive failures. The
+/// breaker recovers on its own once `cooldown` has passed, and is reset by the
+/// next success.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    max_failures: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    opened_at: Option<SystemTime>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_failures: u32, cooldown: Duration) -> Self {
+        assert_ne!(max_failures, 0, "max_failures must be greater than 0");
+        Self {
+            max_failures,
+            cooldown,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub fn state(&self) -> CircuitBreakerState {
+        match self.opened_at {
+            None => CircuitBreakerState::Closed,
+            Some(opened_at) => {
+                let elapsed = now().duration_since(opened_at).unwrap_or_default();
+                if elapsed < self.cooldown {
+                    CircuitBreakerState::Open
+                } else {
+                    CircuitBreakerState::HalfOpen
+                }
+            }
+        }
+    }
+
+    pub fn allows_requests(&self) -> bool {
+        self.state() != CircuitBreakerState::Open
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures >= self.max_failures {
+            warn!(
+                "Opening circuit breaker after {} consecutive failures",
+                self.consecutive_failures
+            );
+            self.opened_at = Some(now());
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::atomic::{AtomicU8, Ordering};
@@ -51,7 +124,7 @@ mod tests {
 
     use anyhow::anyhow;
 
-    use super::retry;
+    use super::{retry, CircuitBreaker, CircuitBreakerState};
 
     #[tokio::test]
     async fn retry_succeed_with_one_attempt() {
@@ -80,4 +153,32 @@ mod tests {
 
         assert_eq!(counter.load(Ordering::SeqCst), 3);
     }
+
+    #[test]
+    fn circuit_breaker_opens_after_max_failures() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(3600));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitBreakerState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitBreakerState::Open);
+        assert!(!breaker.allows_requests());
+
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitBreakerState::Closed);
+    }
+
+    #[test]
+    fn circuit_breaker_recovers_after_cooldown() {
+        let mut breaker = CircuitBreaker::new(1, Duration::ZERO);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitBreakerState::HalfOpen);
+        assert!(breaker.allows_requests());
+
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitBreakerState::Closed);
+    }
 }
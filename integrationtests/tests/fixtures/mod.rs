@@ -150,7 +150,7 @@ pub async fn fixtures(num_peers: u16) -> anyhow::Result<Fixtures> {
     let decoders = module_decode_stubs();
 
     let server_module_inits = ServerModuleGenRegistry::from(vec![
-        DynServerModuleGen::from(WalletGen),
+        DynServerModuleGen::from(WalletGen::default()),
         DynServerModuleGen::from(MintGen),
         DynServerModuleGen::from(LightningGen),
     ]);
@@ -1,10 +1,13 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
-use bitcoin::Network;
+use anyhow::{bail, ensure};
+use bitcoin::{AddressType, Network, PackedLockTime};
 use fedimint_core::bitcoinrpc::BitcoinRpcConfig;
+use fedimint_core::config::ServerModuleConsensusConfig;
 use fedimint_core::core::ModuleKind;
 use fedimint_core::encoding::{Decodable, Encodable};
 use fedimint_core::module::__reexports::serde_json;
+use fedimint_core::module::ModuleConsensusVersion;
 use fedimint_core::{plugin_types_trait_impl_config, Feerate, PeerId};
 use miniscript::descriptor::Wsh;
 use secp256k1::SecretKey;
@@ -32,6 +35,9 @@ impl WalletGenParams {
                     url: Url::parse("http://127.0.0.1:50002/")
                         .expect("Failed to parse default esplora server"),
                 },
+                output_ordering: OutputOrdering::default(),
+                allowed_address_types: None,
+                lock_time: PegOutLockTime::default(),
             },
         }
     }
@@ -48,6 +54,15 @@ pub struct WalletGenParamsConsensus {
     pub finality_delay: u32,
     /// See [`WalletConfigConsensus::client_default_bitcoin_rpc`].
     pub client_default_bitcoin_rpc: BitcoinRpcConfig,
+    /// See [`WalletConfigConsensus::output_ordering`].
+    #[serde(default)]
+    pub output_ordering: OutputOrdering,
+    /// See [`WalletConfigConsensus::allowed_address_types`].
+    #[serde(default)]
+    pub allowed_address_types: Option<BTreeSet<PegOutAddressType>>,
+    /// See [`WalletConfigConsensus::lock_time`].
+    #[serde(default)]
+    pub lock_time: PegOutLockTime,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -57,10 +72,24 @@ pub struct WalletConfig {
     pub consensus: WalletConfigConsensus,
 }
 
+/// Default for [`WalletConfigLocal::fee_spread_warn_threshold`]
+pub const DEFAULT_FEE_SPREAD_WARN_THRESHOLD: Feerate = Feerate {
+    sats_per_kvb: 20_000,
+};
+
 #[derive(Clone, Debug, Serialize, Deserialize, Decodable, Encodable)]
 pub struct WalletConfigLocal {
     /// Configures which bitcoin RPC to use
     pub bitcoin_rpc: BitcoinRpcConfig,
+    /// Warn if the highest and lowest fee rate voted for by the peers differ
+    /// by more than this, which hints at diverging mempools or a broken
+    /// bitcoin backend
+    #[serde(default = "default_fee_spread_warn_threshold")]
+    pub fee_spread_warn_threshold: Feerate,
+}
+
+fn default_fee_spread_warn_threshold() -> Feerate {
+    DEFAULT_FEE_SPREAD_WARN_THRESHOLD
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -93,6 +122,76 @@ pub struct WalletConfigConsensus {
     /// **This is only used by the client, the RPC used by the server is defined
     /// in [`WalletConfigLocal`].**
     pub client_default_bitcoin_rpc: BitcoinRpcConfig,
+    /// How the outputs of peg-out transactions are ordered
+    #[serde(default)]
+    pub output_ordering: OutputOrdering,
+    /// If set, peg-outs are only allowed to addresses of these types
+    #[serde(default)]
+    pub allowed_address_types: Option<BTreeSet<PegOutAddressType>>,
+    /// How the nLockTime of peg-out transactions is set
+    #[serde(default = "legacy_lock_time")]
+    pub lock_time: PegOutLockTime,
+}
+
+/// Peg-outs of federations whose config predates
+/// [`WalletConfigConsensus::lock_time`] have no lock time
+fn legacy_lock_time() -> PegOutLockTime {
+    PegOutLockTime::Zero
+}
+
+/// [`WalletConfigConsensus`] as encoded in consensus version 0, before the
+/// peg-out policy was configurable
+#[derive(Encodable, Decodable)]
+struct WalletConfigConsensusV0 {
+    network: Network,
+    peg_in_descriptor: PegInDescriptor,
+    peer_peg_in_keys: BTreeMap<PeerId, CompressedPublicKey>,
+    finality_delay: u32,
+    default_fee: Feerate,
+    fee_consensus: FeeConsensus,
+    client_default_bitcoin_rpc: BitcoinRpcConfig,
+}
+
+impl From<WalletConfigConsensusV0> for WalletConfigConsensus {
+    /// Keeps building peg-outs the way federations did before they could
+    /// choose a peg-out policy, so their transactions don't change
+    fn from(config: WalletConfigConsensusV0) -> Self {
+        Self {
+            network: config.network,
+            peg_in_descriptor: config.peg_in_descriptor,
+            peer_peg_in_keys: config.peer_peg_in_keys,
+            finality_delay: config.finality_delay,
+            default_fee: config.default_fee,
+            fee_consensus: config.fee_consensus,
+            client_default_bitcoin_rpc: config.client_default_bitcoin_rpc,
+            output_ordering: OutputOrdering::PegOutFirst,
+            allowed_address_types: None,
+            lock_time: legacy_lock_time(),
+        }
+    }
+}
+
+impl WalletConfigConsensus {
+    /// Decodes the consensus config in the encoding of the consensus version
+    /// it was generated with
+    fn from_erased_versioned(erased: &ServerModuleConsensusConfig) -> anyhow::Result<Self> {
+        let modules = Default::default();
+        let mut config = &erased.config[..];
+        let decoded: Self = match erased.version {
+            ModuleConsensusVersion(0) => {
+                WalletConfigConsensusV0::consensus_decode(&mut config, &modules)?.into()
+            }
+            ModuleConsensusVersion(1) => Self::consensus_decode(&mut config, &modules)?,
+            version => bail!("Unsupported wallet consensus version {version:?}"),
+        };
+        // Bytes left over mean the config was encoded with a different version
+        ensure!(
+            config.is_empty(),
+            "Wallet consensus config is longer than consensus version {:?} allows",
+            erased.version
+        );
+        Ok(decoded)
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
@@ -136,7 +235,88 @@ impl Default for FeeConsensus {
     }
 }
 
+/// Order of the outputs in a peg-out transaction. Every peer has to build the
+/// exact same transaction, so any ordering has to be deterministic.
+#[derive(
+    Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable,
+)]
+pub enum OutputOrdering {
+    /// The peg-out output followed by the change output
+    #[default]
+    PegOutFirst,
+    /// Outputs are sorted by a hash of the output seeded with the change
+    /// tweak, which all peers agree on, so the position of the change output
+    /// does not reveal it to outside observers. The change tweak is the hash
+    /// of a peg-out counter, so anyone holding the peg-in descriptor, including
+    /// every client of the federation, can still recognize the change output.
+    SeededShuffle,
+}
+
+/// nLockTime policy of peg-out transactions. Every peer has to build the exact
+/// same transaction, so the lock time can only depend on values all peers agree
+/// on.
+#[derive(
+    Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable,
+)]
+pub enum PegOutLockTime {
+    /// The consensus block height, which discourages fee sniping like the
+    /// transactions of most wallets do and keeps peg-outs from standing out.
+    /// Default for newly generated configs.
+    #[default]
+    ConsensusHeight,
+    /// No lock time, used by configs generated before the lock time was
+    /// configurable
+    Zero,
+}
+
+impl PegOutLockTime {
+    /// The nLockTime of a peg-out built at `consensus_height`
+    pub fn at_height(self, consensus_height: u32) -> PackedLockTime {
+        match self {
+            PegOutLockTime::ConsensusHeight => PackedLockTime(consensus_height),
+            PegOutLockTime::Zero => PackedLockTime::ZERO,
+        }
+    }
+}
+
+/// Address types a federation can restrict peg-outs to, mirrors
+/// [`bitcoin::AddressType`]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    Encodable,
+    Decodable,
+)]
+pub enum PegOutAddressType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+}
+
+impl From<PegOutAddressType> for AddressType {
+    fn from(address_type: PegOutAddressType) -> Self {
+        match address_type {
+            PegOutAddressType::P2pkh => AddressType::P2pkh,
+            PegOutAddressType::P2sh => AddressType::P2sh,
+            PegOutAddressType::P2wpkh => AddressType::P2wpkh,
+            PegOutAddressType::P2wsh => AddressType::P2wsh,
+            PegOutAddressType::P2tr => AddressType::P2tr,
+        }
+    }
+}
+
 impl WalletConfig {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         pubkeys: BTreeMap<PeerId, CompressedPublicKey>,
         sk: SecretKey,
@@ -145,13 +325,19 @@ impl WalletConfig {
         finality_delay: u32,
         bitcoin_rpc: BitcoinRpcConfig,
         client_default_bitcoin_rpc: BitcoinRpcConfig,
+        output_ordering: OutputOrdering,
+        allowed_address_types: Option<BTreeSet<PegOutAddressType>>,
+        lock_time: PegOutLockTime,
     ) -> Self {
         let peg_in_descriptor = PegInDescriptor::Wsh(
             Wsh::new_sortedmulti(threshold, pubkeys.values().copied().collect()).unwrap(),
         );
 
         Self {
-            local: WalletConfigLocal { bitcoin_rpc },
+            local: WalletConfigLocal {
+                bitcoin_rpc,
+                fee_spread_warn_threshold: DEFAULT_FEE_SPREAD_WARN_THRESHOLD,
+            },
             private: WalletConfigPrivate { peg_in_key: sk },
             consensus: WalletConfigConsensus {
                 network,
@@ -161,6 +347,9 @@ impl WalletConfig {
                 default_fee: Feerate { sats_per_kvb: 1000 },
                 fee_consensus: Default::default(),
                 client_default_bitcoin_rpc,
+                output_ordering,
+                allowed_address_types,
+                lock_time,
             },
         }
     }
@@ -192,5 +381,180 @@ plugin_types_trait_impl_config!(
     WalletConfigLocal,
     WalletConfigPrivate,
     WalletConfigConsensus,
-    WalletClientConfig
+    WalletClientConfig,
+    from_erased = WalletConfigConsensus::from_erased_versioned
 );
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::str::FromStr;
+
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::Network;
+    use fedimint_core::bitcoinrpc::BitcoinRpcConfig;
+    use fedimint_core::config::{ServerModuleConsensusConfig, TypedServerModuleConsensusConfig};
+    use fedimint_core::encoding::{Decodable, Encodable};
+    use fedimint_core::module::__reexports::serde_json;
+    use fedimint_core::module::registry::ModuleDecoderRegistry;
+    use fedimint_core::module::ModuleConsensusVersion;
+    use fedimint_core::{Feerate, PeerId};
+    use miniscript::descriptor::Wsh;
+    use url::Url;
+
+    use crate::config::{
+        FeeConsensus, OutputOrdering, PegOutAddressType, PegOutLockTime, WalletConfigConsensus,
+    };
+    use crate::keys::CompressedPublicKey;
+    use crate::{PegInDescriptor, KIND};
+
+    /// Encoding of [`test_config`], every peer stores its consensus config in
+    /// this encoding so it must not change without a consensus version bump
+    const TEST_CONFIG_HEX: &str =
+        "fedab5bffa5f77736828736f727465646d756c746928312c303237396265363637656639646362626163\
+        353561303632393563653837306230373032396266636462326463653238643935396632383135623136\
+        6638313739382929236b6d6e747570686c01000279be667ef9dcbbac55a06295ce870b07029bfcdb2dce\
+        28d959f2815b16f817980afd03e80000076573706c6f726117687474703a2f2f3132372e302e302e313a\
+        35303030322f000000";
+
+    /// Encoding of [`test_config`] in consensus version 0, which did not
+    /// include the peg-out policy
+    const TEST_CONFIG_V0_HEX: &str =
+        "fedab5bffa5f77736828736f727465646d756c746928312c303237396265363637656639646362626163\
+        353561303632393563653837306230373032396266636462326463653238643935396632383135623136\
+        6638313739382929236b6d6e747570686c01000279be667ef9dcbbac55a06295ce870b07029bfcdb2dce\
+        28d959f2815b16f817980afd03e80000076573706c6f726117687474703a2f2f3132372e302e302e313a\
+        35303030322f";
+
+    fn erased(version: u32, hex: &str) -> ServerModuleConsensusConfig {
+        ServerModuleConsensusConfig {
+            kind: KIND,
+            version: ModuleConsensusVersion(version),
+            config: Vec::from_hex(hex).unwrap(),
+        }
+    }
+
+    fn test_config() -> WalletConfigConsensus {
+        let key = CompressedPublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+
+        WalletConfigConsensus {
+            network: Network::Regtest,
+            peg_in_descriptor: PegInDescriptor::Wsh(Wsh::new_sortedmulti(1, vec![key]).unwrap()),
+            peer_peg_in_keys: BTreeMap::from([(PeerId::from(0), key)]),
+            finality_delay: 10,
+            default_fee: Feerate { sats_per_kvb: 1000 },
+            fee_consensus: FeeConsensus::default(),
+            client_default_bitcoin_rpc: BitcoinRpcConfig {
+                kind: "esplora".to_string(),
+                url: Url::parse("http://127.0.0.1:50002/").unwrap(),
+            },
+            output_ordering: OutputOrdering::PegOutFirst,
+            allowed_address_types: None,
+            lock_time: PegOutLockTime::ConsensusHeight,
+        }
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_encoding_is_stable() {
+        assert_eq!(
+            test_config().consensus_encode_to_hex().unwrap(),
+            TEST_CONFIG_HEX
+        );
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_roundtrips() {
+        let decoded = WalletConfigConsensus::consensus_decode_hex(
+            TEST_CONFIG_HEX,
+            &ModuleDecoderRegistry::default(),
+        )
+        .unwrap();
+
+        assert_eq!(decoded.consensus_encode_to_hex().unwrap(), TEST_CONFIG_HEX);
+        assert_eq!(decoded.peg_in_descriptor, test_config().peg_in_descriptor);
+        assert_eq!(decoded.lock_time, PegOutLockTime::ConsensusHeight);
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_decodes_current_version() {
+        let config = WalletConfigConsensus::from_erased(&erased(1, TEST_CONFIG_HEX)).unwrap();
+        assert_eq!(config.consensus_encode_to_hex().unwrap(), TEST_CONFIG_HEX);
+        assert_eq!(config.version(), ModuleConsensusVersion(1));
+
+        assert!(WalletConfigConsensus::from_erased(&erased(2, TEST_CONFIG_HEX)).is_err());
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_version_0_keeps_peg_outs_without_lock_time() {
+        let config = WalletConfigConsensus::from_erased(&erased(0, TEST_CONFIG_V0_HEX)).unwrap();
+        assert_eq!(config.peg_in_descriptor, test_config().peg_in_descriptor);
+        assert_eq!(config.lock_time, PegOutLockTime::Zero);
+
+        // Configs with the current encoding never decode as version 0 and
+        // vice versa
+        assert!(WalletConfigConsensus::from_erased(&erased(0, TEST_CONFIG_HEX)).is_err());
+        assert!(WalletConfigConsensus::from_erased(&erased(1, TEST_CONFIG_V0_HEX)).is_err());
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_json_without_lock_time_has_no_lock_time() {
+        let mut json = serde_json::to_value(test_config()).unwrap();
+        json.as_object_mut().unwrap().remove("lock_time");
+
+        let config: WalletConfigConsensus = serde_json::from_value(json).unwrap();
+        assert_eq!(config.lock_time, PegOutLockTime::Zero);
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_version_0_keeps_peg_out_output_first() {
+        let config = WalletConfigConsensus::from_erased(&erased(0, TEST_CONFIG_V0_HEX)).unwrap();
+        assert_eq!(config.output_ordering, OutputOrdering::PegOutFirst);
+
+        let mut json = serde_json::to_value(WalletConfigConsensus {
+            output_ordering: OutputOrdering::SeededShuffle,
+            ..test_config()
+        })
+        .unwrap();
+        json.as_object_mut().unwrap().remove("output_ordering");
+
+        let config: WalletConfigConsensus = serde_json::from_value(json).unwrap();
+        assert_eq!(config.output_ordering, OutputOrdering::PegOutFirst);
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_encodes_allowed_address_types() {
+        let config = WalletConfigConsensus {
+            allowed_address_types: Some(BTreeSet::from([
+                PegOutAddressType::P2tr,
+                PegOutAddressType::P2wpkh,
+            ])),
+            ..test_config()
+        };
+        let expected_hex = format!("{TEST_CONFIG_V0_HEX}000102020400");
+        assert_eq!(config.consensus_encode_to_hex().unwrap(), expected_hex);
+
+        let decoded = WalletConfigConsensus::from_erased(&erased(1, &expected_hex)).unwrap();
+        assert_eq!(decoded.allowed_address_types, config.allowed_address_types);
+    }
+
+    #[test_log::test]
+    fn wallet_config_consensus_version_0_allows_all_address_types() {
+        let config = WalletConfigConsensus::from_erased(&erased(0, TEST_CONFIG_V0_HEX)).unwrap();
+        assert_eq!(config.allowed_address_types, None);
+
+        let mut json = serde_json::to_value(WalletConfigConsensus {
+            allowed_address_types: Some(BTreeSet::from([PegOutAddressType::P2wpkh])),
+            ..test_config()
+        })
+        .unwrap();
+        json.as_object_mut()
+            .unwrap()
+            .remove("allowed_address_types");
+
+        let config: WalletConfigConsensus = serde_json::from_value(json).unwrap();
+        assert_eq!(config.allowed_address_types, None);
+    }
+}
@@ -26,7 +26,9 @@ pub mod tweakable;
 pub mod txoproof;
 
 pub const KIND: ModuleKind = ModuleKind::from_static_str("wallet");
-const CONSENSUS_VERSION: ModuleConsensusVersion = ModuleConsensusVersion(0);
+/// Version 1 added the peg-out policy to
+/// [`config::WalletConfigConsensus`], configs of version 0 are still supported
+const CONSENSUS_VERSION: ModuleConsensusVersion = ModuleConsensusVersion(1);
 
 pub const CONFIRMATION_TARGET: u16 = 10;
 
@@ -150,9 +152,57 @@ impl PegOutFees {
         }
     }
 
+    /// The fee in whole satoshis, rounded down. This is the fee actually paid
+    /// on-chain.
     pub fn amount(&self) -> Amount {
         self.fee_rate.calculate_fee(self.total_weight)
     }
+
+    /// The fee with millisatoshi precision, for reconciling quoted against
+    /// actual fees. Rounding down to whole satoshis yields [`Self::amount`].
+    pub fn amount_msats(&self) -> fedimint_core::Amount {
+        self.fee_rate.calculate_fee_msats(self.total_weight)
+    }
+}
+
+/// Describes how fragmented the federation's spendable UTXOs are
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct UtxoStats {
+    pub count: usize,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub total: bitcoin::Amount,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub smallest: bitcoin::Amount,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub largest: bitcoin::Amount,
+    /// For an even number of UTXOs the mean of the two middle values, rounded
+    /// down
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub median_value: bitcoin::Amount,
+}
+
+impl UtxoStats {
+    /// All amounts are zero if there are no UTXOs
+    pub fn from_amounts(amounts: impl IntoIterator<Item = bitcoin::Amount>) -> Self {
+        let mut amounts = amounts.into_iter().collect::<Vec<_>>();
+        amounts.sort();
+
+        let median_value = match amounts.len() {
+            0 => bitcoin::Amount::ZERO,
+            len if len % 2 == 1 => amounts[len / 2],
+            len => bitcoin::Amount::from_sat(
+                (amounts[len / 2 - 1].to_sat() + amounts[len / 2].to_sat()) / 2,
+            ),
+        };
+
+        UtxoStats {
+            count: amounts.len(),
+            total: bitcoin::Amount::from_sat(amounts.iter().map(|amount| amount.to_sat()).sum()),
+            smallest: amounts.first().copied().unwrap_or(bitcoin::Amount::ZERO),
+            largest: amounts.last().copied().unwrap_or(bitcoin::Amount::ZERO),
+            median_value,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
@@ -299,6 +349,15 @@ pub enum WalletError {
     TxWeightIncorrect(u64, u64),
     #[error("Peg-out fee rate is below min relay fee")]
     BelowMinRelayFee,
+    #[error("Invalid peg-out PSBT: {0}")]
+    InvalidPsbt(String),
+    #[error(
+        "Peg-outs to {} addresses are not allowed",
+        .0.address_type().map_or("unknown".to_string(), |t| t.to_string())
+    )]
+    DisallowedAddressType(bitcoin::Address),
+    #[error("Outpoint {0} is not known to the wallet")]
+    UnknownOutPoint(bitcoin::OutPoint),
 }
 
 #[derive(Debug, Error)]
@@ -331,3 +390,53 @@ impl PartialEq for WalletError {
 
 /// **WARNING**: this is only intended to be used for testing
 impl Eq for WalletError {}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+    use fedimint_core::encoding::{Decodable, Encodable};
+    use fedimint_core::module::registry::ModuleDecoderRegistry;
+    use fedimint_core::Feerate;
+
+    use crate::{PegOutSignatureItem, WalletConsensusItem};
+
+    /// Peers exchange consensus items in this encoding, so it must not change
+    /// without a consensus version bump
+    fn assert_encoding(item: WalletConsensusItem, expected_hex: &str) {
+        assert_eq!(item.consensus_encode_to_hex().unwrap(), expected_hex);
+
+        let decoded = WalletConsensusItem::consensus_decode_hex(
+            expected_hex,
+            &ModuleDecoderRegistry::default(),
+        )
+        .unwrap();
+        assert_eq!(decoded, item);
+    }
+
+    #[test_log::test]
+    fn wallet_consensus_item_encoding_is_stable() {
+        assert_encoding(WalletConsensusItem::BlockHeight(42), "002a");
+        assert_encoding(
+            WalletConsensusItem::Feerate(Feerate { sats_per_kvb: 1000 }),
+            "01fd03e8",
+        );
+        assert_encoding(
+            WalletConsensusItem::PegOutSignature(PegOutSignatureItem {
+                txid: Txid::from_inner([2; 32]),
+                signature: vec![secp256k1::ecdsa::Signature::from_compact(&[1; 64]).unwrap()],
+            }),
+            "0202020202020202020202020202020202020202020202020202020202020202020101010101010101\
+            0101010101010101010101010101010101010101010101010101010101010101010101010101010101\
+            01010101010101010101010101010101",
+        );
+    }
+
+    #[test_log::test]
+    fn wallet_consensus_item_rejects_unknown_variant() {
+        assert!(
+            WalletConsensusItem::consensus_decode_hex("03", &ModuleDecoderRegistry::default())
+                .is_err()
+        );
+    }
+}
@@ -1,17 +1,20 @@
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::{Infallible, TryInto};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 #[cfg(not(target_family = "wasm"))]
 use std::time::Duration;
 
 use anyhow::{bail, format_err, Context};
 use bitcoin::hashes::{sha256, Hash as BitcoinHash, HashEngine, Hmac, HmacEngine};
 use bitcoin::policy::DEFAULT_MIN_RELAY_TX_FEE;
-use bitcoin::secp256k1::{All, Secp256k1, Verification};
+use bitcoin::secp256k1::{All, Secp256k1, Signing, Verification};
 use bitcoin::util::psbt::{Input, PartiallySignedTransaction};
 use bitcoin::util::sighash::SighashCache;
 use bitcoin::{
-    Address, BlockHash, EcdsaSig, EcdsaSighashType, Network, PackedLockTime, Script, Sequence,
-    Transaction, TxIn, TxOut, Txid,
+    Address, AddressType, BlockHash, EcdsaSig, EcdsaSighashType, Network, PackedLockTime, Script,
+    Sequence, Transaction, TxIn, TxOut, Txid,
 };
 use common::config::WalletConfigConsensus;
 use common::db::{
@@ -20,7 +23,7 @@ use common::db::{
 };
 use common::{
     proprietary_tweak_key, PegOutFees, PegOutSignatureItem, PendingTransaction,
-    ProcessPegOutSigError, SpendableUTXO, UnsignedTransaction, WalletCommonGen,
+    ProcessPegOutSigError, SpendableUTXO, UnsignedTransaction, UtxoStats, WalletCommonGen,
     WalletConsensusItem, WalletError, WalletInput, WalletModuleTypes, WalletOutput,
     WalletOutputOutcome, CONFIRMATION_TARGET,
 };
@@ -49,7 +52,9 @@ use fedimint_core::{
 };
 use fedimint_server::config::distributedgen::PeerHandleOps;
 pub use fedimint_wallet_common as common;
-use fedimint_wallet_common::config::{WalletClientConfig, WalletConfig, WalletGenParams};
+use fedimint_wallet_common::config::{
+    OutputOrdering, PegOutAddressType, WalletClientConfig, WalletConfig, WalletGenParams,
+};
 use fedimint_wallet_common::db::{
     BlockHashKey, BlockHashKeyPrefix, PegOutBitcoinTransaction, PegOutBitcoinTransactionPrefix,
     PegOutTxSignatureCI, PegOutTxSignatureCIPrefix, PendingTransactionKey,
@@ -68,8 +73,22 @@ use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use tracing::{debug, info, instrument, trace, warn};
 
-#[derive(Debug, Clone)]
-pub struct WalletGen;
+/// Magic bytes every serialized PSBT starts with (BIP-174)
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+#[derive(Debug, Clone, Default)]
+pub struct WalletGen {
+    fee_estimator: Option<Arc<dyn FeeEstimator>>,
+}
+
+impl WalletGen {
+    /// Makes every wallet initialized by this generator source its fee rate
+    /// estimates from `fee_estimator` instead of the bitcoin backend
+    pub fn with_fee_estimator(mut self, fee_estimator: Arc<dyn FeeEstimator>) -> Self {
+        self.fee_estimator = Some(fee_estimator);
+        self
+    }
+}
 
 impl ExtendsCommonModuleGen for WalletGen {
     type Common = WalletCommonGen;
@@ -81,7 +100,7 @@ impl ServerModuleGen for WalletGen {
     const DATABASE_VERSION: DatabaseVersion = DatabaseVersion(0);
 
     fn versions(&self, _core: CoreConsensusVersion) -> &[ModuleConsensusVersion] {
-        &[ModuleConsensusVersion(0)]
+        &[ModuleConsensusVersion(0), ModuleConsensusVersion(1)]
     }
 
     fn supported_api_versions(&self) -> SupportedModuleApiVersions {
@@ -94,7 +113,11 @@ impl ServerModuleGen for WalletGen {
         db: Database,
         task_group: &mut TaskGroup,
     ) -> anyhow::Result<DynServerModule> {
-        Ok(Wallet::new(cfg.to_typed()?, db, task_group).await?.into())
+        let mut wallet = Wallet::new(cfg.to_typed()?, db, task_group).await?;
+        if let Some(fee_estimator) = &self.fee_estimator {
+            wallet = wallet.with_fee_estimator(fee_estimator.clone());
+        }
+        Ok(wallet.into())
     }
 
     fn trusted_dealer_gen(
@@ -124,6 +147,9 @@ impl ServerModuleGen for WalletGen {
                     params.consensus.finality_delay,
                     params.local.bitcoin_rpc.clone(),
                     params.consensus.client_default_bitcoin_rpc.clone(),
+                    params.consensus.output_ordering,
+                    params.consensus.allowed_address_types.clone(),
+                    params.consensus.lock_time,
                 );
                 (*id, cfg)
             })
@@ -159,6 +185,9 @@ impl ServerModuleGen for WalletGen {
             params.consensus.finality_delay,
             params.local.bitcoin_rpc.clone(),
             params.consensus.client_default_bitcoin_rpc.clone(),
+            params.consensus.output_ordering,
+            params.consensus.allowed_address_types.clone(),
+            params.consensus.lock_time,
         );
 
         Ok(wallet_cfg.to_erased())
@@ -481,6 +510,14 @@ impl ServerModule for Wallet {
         dbtx: &mut ModuleDatabaseTransaction<'_>,
         output: &WalletOutput,
     ) -> Result<TransactionItemAmount, ModuleError> {
+        if let WalletOutput::PegOut(peg_out) = output {
+            validate_address_type(
+                &peg_out.recipient,
+                self.cfg.consensus.allowed_address_types.as_ref(),
+            )
+            .into_module_error_other()?;
+        }
+
         let dummy_tweak = [0; 32];
 
         let fee_rate = self.consensus_fee_rate(dbtx).await;
@@ -609,9 +646,10 @@ impl ServerModule for Wallet {
                         bitcoin::Amount::from_sat(sats),
                         address.script_pubkey(),
                         vec![],
-                        module.available_utxos(&mut context.dbtx()).await,
+                        available_utxos(&mut context.dbtx()).await,
                         feerate,
                         &dummy_tweak,
+                        module.peg_out_lock_time(&mut context.dbtx()).await,
                         None
                     );
 
@@ -634,6 +672,64 @@ pub struct Wallet {
     cfg: WalletConfig,
     secp: Secp256k1<All>,
     btc_rpc: DynBitcoindRpc,
+    fee_estimator: Arc<dyn FeeEstimator>,
+    call_counters: Arc<BitcoindCallCounters>,
+    last_round_fee_spread: Mutex<Option<Feerate>>,
+}
+
+/// Source of the fee rate the wallet proposes for consensus, by default the
+/// bitcoin backend that is also used to track the chain
+#[apply(async_trait_maybe_send!)]
+pub trait FeeEstimator: Debug + Send + Sync {
+    /// Fee rate to confirm within `confirmation_target` blocks, `None` if no
+    /// estimate is available
+    async fn estimate(&self, confirmation_target: u16) -> anyhow::Result<Option<Feerate>>;
+}
+
+/// The default [`FeeEstimator`], counting its calls to the bitcoin backend
+#[derive(Debug)]
+struct BitcoindFeeEstimator {
+    btc_rpc: DynBitcoindRpc,
+    call_counters: Arc<BitcoindCallCounters>,
+}
+
+#[apply(async_trait_maybe_send!)]
+impl FeeEstimator for BitcoindFeeEstimator {
+    async fn estimate(&self, confirmation_target: u16) -> anyhow::Result<Option<Feerate>> {
+        self.call_counters
+            .get_fee_rate
+            .fetch_add(1, Ordering::Relaxed);
+        self.btc_rpc.get_fee_rate(confirmation_target).await
+    }
+}
+
+/// Number of calls the wallet made to its bitcoin backend since startup, per
+/// RPC method. Calls made by the background broadcaster are not included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct WalletCallStats {
+    pub get_block_height: u64,
+    pub get_fee_rate: u64,
+    pub get_block_hash: u64,
+    pub get_tx_block_height: u64,
+}
+
+#[derive(Debug, Default)]
+struct BitcoindCallCounters {
+    get_block_height: AtomicU64,
+    get_fee_rate: AtomicU64,
+    get_block_hash: AtomicU64,
+    get_tx_block_height: AtomicU64,
+}
+
+impl BitcoindCallCounters {
+    fn stats(&self) -> WalletCallStats {
+        WalletCallStats {
+            get_block_height: self.get_block_height.load(Ordering::Relaxed),
+            get_fee_rate: self.get_fee_rate.load(Ordering::Relaxed),
+            get_block_hash: self.get_block_hash.load(Ordering::Relaxed),
+            get_tx_block_height: self.get_tx_block_height.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl Wallet {
@@ -688,10 +784,17 @@ impl Wallet {
             Err(err) => warn!("Bitcoin fee estimation failed. Please configure your nodes to enable fee estimation: {:?}", err),
         }
 
+        let call_counters = Arc::new(BitcoindCallCounters::default());
         let wallet = Wallet {
             cfg,
             secp: Default::default(),
+            fee_estimator: Arc::new(BitcoindFeeEstimator {
+                btc_rpc: bitcoind_rpc.clone(),
+                call_counters: call_counters.clone(),
+            }),
             btc_rpc: bitcoind_rpc,
+            call_counters,
+            last_round_fee_spread: Mutex::new(None),
         };
 
         Ok(wallet)
@@ -771,15 +874,8 @@ impl Wallet {
         // later on. The tweak is extracted here because the psbt is moved next
         // and not available anymore when the tweak is actually needed in the
         // end to be put into the batch on success.
-        let change_tweak: [u8; 32] = unsigned
-            .psbt
-            .outputs
-            .iter()
-            .flat_map(|output| output.proprietary.get(&proprietary_tweak_key()).cloned())
-            .next()
-            .ok_or(ProcessPegOutSigError::MissingOrMalformedChangeTweak)?
-            .try_into()
-            .map_err(|_| ProcessPegOutSigError::MissingOrMalformedChangeTweak)?;
+        let change_tweak = change_tweak(&unsigned.psbt)
+            .ok_or(ProcessPegOutSigError::MissingOrMalformedChangeTweak)?;
 
         if let Err(error) = unsigned.psbt.finalize_mut(&self.secp) {
             return Err(ProcessPegOutSigError::ErrorFinalizingPsbt(error));
@@ -799,7 +895,76 @@ impl Wallet {
         })
     }
 
+    /// Serializes a peg-out PSBT into the standard base64 format consumed by
+    /// hardware wallets and air-gapped signers
+    pub fn pegout_psbt_base64(&self, psbt: &PartiallySignedTransaction) -> String {
+        base64::encode(bitcoin::consensus::encode::serialize(psbt))
+    }
+
+    /// Signs a base64 encoded peg-out PSBT with our peg-in key and returns it
+    /// base64 encoded again, so it can be round-tripped through an external
+    /// signer. Only peg-outs the federation agreed on and that are still
+    /// awaiting signatures are signed.
+    pub async fn sign_pegout_psbt_base64(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_>,
+        b64: &str,
+    ) -> Result<String, WalletError> {
+        let mut psbt = decode_pegout_psbt_base64(b64)?;
+        let unsigned = dbtx
+            .get_value(&UnsignedTransactionKey(psbt.unsigned_tx.txid()))
+            .await
+            .ok_or_else(|| {
+                WalletError::InvalidPsbt("not a peg-out awaiting signatures".to_string())
+            })?;
+        verify_pegout_psbt(&psbt, &unsigned.psbt)?;
+
+        self.offline_wallet().sign_psbt(&mut psbt);
+        Ok(self.pegout_psbt_base64(&psbt))
+    }
+
+    /// Merges the partial signatures of PSBTs signed by different peers, which
+    /// all have to be for the same unsigned peg-out transaction
+    pub fn combine_pegout_psbts(
+        &self,
+        psbts: Vec<PartiallySignedTransaction>,
+    ) -> Result<PartiallySignedTransaction, WalletError> {
+        combine_pegout_psbts(psbts)
+    }
+
+    /// Peg-in keys of the peers whose signatures are still missing on `psbt`,
+    /// empty once every input carries a threshold of signatures
+    pub fn missing_signatures(
+        &self,
+        psbt: &PartiallySignedTransaction,
+    ) -> Result<Vec<CompressedPublicKey>, WalletError> {
+        missing_signatures(psbt, &self.cfg.consensus.peer_peg_in_keys, &self.secp)
+    }
+
+    /// Returns the BIP-380 checksum of the peg-in descriptor. Operators can
+    /// compare this short string out-of-band to make sure all peers loaded
+    /// the same descriptor.
+    pub fn descriptor_checksum(&self) -> String {
+        peg_in_descriptor_checksum(&self.cfg.consensus.peg_in_descriptor)
+    }
+
+    /// Sources fee rate estimates from `fee_estimator` instead of the bitcoin
+    /// backend, its calls don't show up in [`Self::call_stats`]
+    pub fn with_fee_estimator(mut self, fee_estimator: Arc<dyn FeeEstimator>) -> Self {
+        self.fee_estimator = fee_estimator;
+        self
+    }
+
+    /// Counts of the calls made to the bitcoin backend, to judge the load the
+    /// wallet puts on it
+    pub fn call_stats(&self) -> WalletCallStats {
+        self.call_counters.stats()
+    }
+
     pub async fn block_height(&self) -> u32 {
+        self.call_counters
+            .get_block_height
+            .fetch_add(1, Ordering::Relaxed);
         self.btc_rpc
             .get_block_height()
             .await
@@ -807,10 +972,10 @@ impl Wallet {
     }
 
     pub async fn fee_rate(&self) -> Feerate {
-        self.btc_rpc
-            .get_fee_rate(CONFIRMATION_TARGET)
+        self.fee_estimator
+            .estimate(CONFIRMATION_TARGET)
             .await
-            .expect("bitcoind rpc failed")
+            .expect("fee estimation failed")
             .unwrap_or(self.cfg.consensus.default_fee)
     }
 
@@ -847,6 +1012,18 @@ impl Wallet {
 
         assert!(rates.len() <= peer_count);
 
+        let spread = fee_rate_spread(&rates);
+        if let Some(spread) = spread {
+            if spread > self.cfg.local.fee_spread_warn_threshold {
+                warn!(
+                    spread = spread.sats_per_kvb,
+                    threshold = self.cfg.local.fee_spread_warn_threshold.sats_per_kvb,
+                    "Peers disagree on the fee rate, check for diverging mempools or a broken bitcoin backend"
+                );
+            }
+        }
+        *self.last_round_fee_spread.lock().expect("lock poisoned") = spread;
+
         while rates.len() < peer_count {
             rates.push(self.cfg.consensus.default_fee);
         }
@@ -856,6 +1033,12 @@ impl Wallet {
         rates[peer_count / 2]
     }
 
+    /// Difference between the highest and lowest fee rate the peers voted for
+    /// when the consensus fee rate was last computed, `None` if no peer voted
+    pub fn last_round_fee_spread(&self) -> Option<Feerate> {
+        *self.last_round_fee_spread.lock().expect("lock poisoned")
+    }
+
     pub async fn consensus_nonce(&self, dbtx: &mut ModuleDatabaseTransaction<'_>) -> [u8; 32] {
         let nonce = dbtx.get_value(&PegOutNonceKey).await.unwrap_or(0);
         dbtx.insert_entry(&PegOutNonceKey, &(nonce + 1)).await;
@@ -882,6 +1065,9 @@ impl Wallet {
 
             // TODO: use batching for mainnet syncing
             trace!(block = height, "Fetching block hash");
+            self.call_counters
+                .get_block_hash
+                .fetch_add(1, Ordering::Relaxed);
             let block_hash = self
                 .btc_rpc
                 .get_block_hash(height as u64)
@@ -896,6 +1082,9 @@ impl Wallet {
                 .await;
 
             for (txid, tx) in &pending_transactions {
+                self.call_counters
+                    .get_tx_block_height
+                    .fetch_add(1, Ordering::Relaxed);
                 if let Ok(Some(tx_height)) = self.btc_rpc.get_tx_block_height(txid).await {
                     if tx_height == height as u64 {
                         self.recognize_change_utxo(dbtx, tx).await;
@@ -920,26 +1109,11 @@ impl Wallet {
     ) {
         self.remove_rbf_transactions(dbtx, pending_tx).await;
 
-        let script_pk = self
-            .cfg
-            .consensus
-            .peg_in_descriptor
-            .tweak(&pending_tx.tweak, &self.secp)
-            .script_pubkey();
-        for (idx, output) in pending_tx.tx.output.iter().enumerate() {
-            if output.script_pubkey == script_pk {
-                dbtx.insert_entry(
-                    &UTXOKey(bitcoin::OutPoint {
-                        txid: pending_tx.tx.txid(),
-                        vout: idx as u32,
-                    }),
-                    &SpendableUTXO {
-                        tweak: pending_tx.tweak,
-                        amount: bitcoin::Amount::from_sat(output.value),
-                    },
-                )
-                .await;
-            }
+        for (key, utxo) in self
+            .offline_wallet()
+            .change_utxos(&pending_tx.tx, pending_tx.tweak)
+        {
+            dbtx.insert_entry(&key, &utxo).await;
         }
     }
 
@@ -1001,9 +1175,10 @@ impl Wallet {
                 peg_out.amount,
                 peg_out.recipient.script_pubkey(),
                 vec![],
-                self.available_utxos(dbtx).await,
+                available_utxos(dbtx).await,
                 peg_out.fees.fee_rate,
                 change_tweak,
+                self.peg_out_lock_time(dbtx).await,
                 None,
             ),
             WalletOutput::Rbf(rbf) => {
@@ -1016,31 +1191,28 @@ impl Wallet {
                     tx.peg_out_amount,
                     tx.destination,
                     tx.selected_utxos,
-                    self.available_utxos(dbtx).await,
+                    available_utxos(dbtx).await,
                     tx.fees.fee_rate,
                     change_tweak,
+                    self.peg_out_lock_time(dbtx).await,
                     Some(rbf.clone()),
                 )
             }
         }
     }
 
-    async fn available_utxos(
-        &self,
-        dbtx: &mut ModuleDatabaseTransaction<'_>,
-    ) -> Vec<(UTXOKey, SpendableUTXO)> {
-        dbtx.find_by_prefix(&UTXOPrefixKey)
-            .await
-            .collect::<Vec<(UTXOKey, SpendableUTXO)>>()
-            .await
+    async fn peg_out_lock_time(&self, dbtx: &mut ModuleDatabaseTransaction<'_>) -> PackedLockTime {
+        self.cfg
+            .consensus
+            .lock_time
+            .at_height(self.consensus_block_height(dbtx).await)
     }
 
     pub async fn get_wallet_value(
         &self,
         dbtx: &mut ModuleDatabaseTransaction<'_>,
     ) -> bitcoin::Amount {
-        let sat_sum = self
-            .available_utxos(dbtx)
+        let sat_sum = available_utxos(dbtx)
             .await
             .into_iter()
             .map(|(_, utxo)| utxo.amount.to_sat())
@@ -1048,13 +1220,263 @@ impl Wallet {
         bitcoin::Amount::from_sat(sat_sum)
     }
 
+    /// Same UTXO set as [`Self::get_wallet_value`], useful to judge whether
+    /// large peg-outs will need many inputs
+    pub async fn matured_utxo_stats(&self, dbtx: &mut ModuleDatabaseTransaction<'_>) -> UtxoStats {
+        UtxoStats::from_amounts(
+            available_utxos(dbtx)
+                .await
+                .into_iter()
+                .map(|(_, utxo)| utxo.amount),
+        )
+    }
+
+    /// Whether `outpoint` is part of the matured UTXO set that backs
+    /// [`Self::get_wallet_value`]. Change outputs of peg-outs that are still
+    /// collecting signatures or weren't confirmed at consensus height yet are
+    /// known but not matured.
+    pub async fn is_outpoint_matured(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_>,
+        outpoint: bitcoin::OutPoint,
+    ) -> Result<bool, WalletError> {
+        is_outpoint_matured(dbtx, &self.offline_wallet(), outpoint).await
+    }
+
+    /// Largest peg-out to `recipient` the federation can currently make, spending
+    /// all matured UTXOs at the consensus fee rate. Zero if they can't even
+    /// cover the fees.
+    pub async fn max_sendable(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_>,
+        recipient: &Address,
+    ) -> Result<bitcoin::Amount, WalletError> {
+        validate_address_type(recipient, self.cfg.consensus.allowed_address_types.as_ref())?;
+
+        let fee_rate = self.consensus_fee_rate(dbtx).await;
+        let utxos = available_utxos(dbtx).await;
+        // The change tweak doesn't change the size of the change script
+        let dummy_tweak = [0; 32];
+
+        Ok(self.offline_wallet().max_peg_out_amount(
+            &recipient.script_pubkey(),
+            &utxos,
+            fee_rate,
+            &dummy_tweak,
+        ))
+    }
+
     fn offline_wallet(&self) -> StatelessWallet {
         StatelessWallet {
             descriptor: &self.cfg.consensus.peg_in_descriptor,
             secret_key: &self.cfg.private.peg_in_key,
             secp: &self.secp,
+            output_ordering: self.cfg.consensus.output_ordering,
+        }
+    }
+}
+
+/// Rejects peg-out recipients whose address type is not in `allowed`, all
+/// types are allowed if it is `None`
+fn validate_address_type(
+    recipient: &Address,
+    allowed: Option<&BTreeSet<PegOutAddressType>>,
+) -> Result<(), WalletError> {
+    let Some(allowed) = allowed else {
+        return Ok(());
+    };
+
+    let address_type = recipient.address_type();
+    if allowed
+        .iter()
+        .any(|allowed_type| Some(AddressType::from(*allowed_type)) == address_type)
+    {
+        Ok(())
+    } else {
+        Err(WalletError::DisallowedAddressType(recipient.clone()))
+    }
+}
+
+/// The matured UTXOs peg-outs can spend. Change of peg-outs is only added to
+/// them once the peg-out is confirmed at consensus height.
+async fn available_utxos(
+    dbtx: &mut ModuleDatabaseTransaction<'_>,
+) -> Vec<(UTXOKey, SpendableUTXO)> {
+    dbtx.find_by_prefix(&UTXOPrefixKey)
+        .await
+        .collect::<Vec<(UTXOKey, SpendableUTXO)>>()
+        .await
+}
+
+async fn is_outpoint_matured(
+    dbtx: &mut ModuleDatabaseTransaction<'_>,
+    wallet: &StatelessWallet<'_>,
+    outpoint: bitcoin::OutPoint,
+) -> Result<bool, WalletError> {
+    if dbtx.get_value(&UTXOKey(outpoint)).await.is_some() {
+        return Ok(true);
+    }
+
+    let is_change = |tx: &Transaction, tweak: [u8; 32]| {
+        wallet
+            .change_utxos(tx, tweak)
+            .into_iter()
+            .any(|(key, _)| key.0 == outpoint)
+    };
+
+    if let Some(pending_tx) = dbtx.get_value(&PendingTransactionKey(outpoint.txid)).await {
+        if is_change(&pending_tx.tx, pending_tx.tweak) {
+            return Ok(false);
+        }
+    }
+
+    if let Some(unsigned) = dbtx.get_value(&UnsignedTransactionKey(outpoint.txid)).await {
+        if let Some(tweak) = change_tweak(&unsigned.psbt) {
+            if is_change(&unsigned.psbt.unsigned_tx, tweak) {
+                return Ok(false);
+            }
+        }
+    }
+
+    Err(WalletError::UnknownOutPoint(outpoint))
+}
+
+/// The tweak of the change output of a peg-out `psbt`, `None` if it is
+/// missing or malformed
+fn change_tweak(psbt: &PartiallySignedTransaction) -> Option<[u8; 32]> {
+    psbt.outputs
+        .iter()
+        .flat_map(|output| output.proprietary.get(&proprietary_tweak_key()))
+        .next()?
+        .clone()
+        .try_into()
+        .ok()
+}
+
+/// Difference between the highest and lowest of the fee rates peers voted for,
+/// `None` if there are no votes
+fn fee_rate_spread(votes: &[Feerate]) -> Option<Feerate> {
+    let min = votes.iter().min()?;
+    let max = votes.iter().max()?;
+    Some(Feerate {
+        sats_per_kvb: max.sats_per_kvb - min.sats_per_kvb,
+    })
+}
+
+/// Returns the BIP-380 checksum miniscript appends when displaying `descriptor`
+fn peg_in_descriptor_checksum(descriptor: &Descriptor<CompressedPublicKey>) -> String {
+    descriptor
+        .to_string()
+        .split('#')
+        .nth(1)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Parses a base64 encoded peg-out PSBT, checking it carries everything
+/// needed to sign it with our peg-in key
+fn decode_pegout_psbt_base64(b64: &str) -> Result<PartiallySignedTransaction, WalletError> {
+    let bytes = base64::decode(b64).map_err(|e| WalletError::InvalidPsbt(e.to_string()))?;
+    if !bytes.starts_with(PSBT_MAGIC) {
+        return Err(WalletError::InvalidPsbt("missing magic bytes".to_string()));
+    }
+
+    let psbt: PartiallySignedTransaction = bitcoin::consensus::encode::deserialize(&bytes)
+        .map_err(|e| WalletError::InvalidPsbt(e.to_string()))?;
+
+    for input in &psbt.inputs {
+        if !input.proprietary.contains_key(&proprietary_tweak_key())
+            || input.witness_script.is_none()
+            || input.witness_utxo.is_none()
+        {
+            return Err(WalletError::InvalidPsbt(
+                "input is missing tweak, witness script or UTXO".to_string(),
+            ));
         }
     }
+
+    Ok(psbt)
+}
+
+/// Checks that `psbt` is the peg-out PSBT `approved` we built ourselves,
+/// possibly carrying signatures of other peers, so signing it can't authorize
+/// any other spend
+fn verify_pegout_psbt(
+    psbt: &PartiallySignedTransaction,
+    approved: &PartiallySignedTransaction,
+) -> Result<(), WalletError> {
+    if psbt.unsigned_tx != approved.unsigned_tx {
+        return Err(WalletError::InvalidPsbt(
+            "transaction differs from the approved peg-out".to_string(),
+        ));
+    }
+
+    // the sighash commits to the spent amounts, which the PSBT carries
+    for (input, approved_input) in psbt.inputs.iter().zip(&approved.inputs) {
+        if input.witness_utxo != approved_input.witness_utxo
+            || input.witness_script != approved_input.witness_script
+            || input.proprietary.get(&proprietary_tweak_key())
+                != approved_input.proprietary.get(&proprietary_tweak_key())
+        {
+            return Err(WalletError::InvalidPsbt(
+                "input differs from the approved peg-out".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn combine_pegout_psbts(
+    psbts: Vec<PartiallySignedTransaction>,
+) -> Result<PartiallySignedTransaction, WalletError> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts
+        .next()
+        .ok_or_else(|| WalletError::InvalidPsbt("no PSBTs to combine".to_string()))?;
+
+    for psbt in psbts {
+        // also fails if the PSBTs are not for the same unsigned tx
+        combined
+            .combine(psbt)
+            .map_err(|e| WalletError::InvalidPsbt(e.to_string()))?;
+    }
+
+    Ok(combined)
+}
+
+fn missing_signatures<C: Verification + Signing>(
+    psbt: &PartiallySignedTransaction,
+    peer_keys: &BTreeMap<PeerId, CompressedPublicKey>,
+    secp: &Secp256k1<C>,
+) -> Result<Vec<CompressedPublicKey>, WalletError> {
+    let mut missing = BTreeSet::new();
+    let mut ready = true;
+
+    for input in &psbt.inputs {
+        let tweak = input
+            .proprietary
+            .get(&proprietary_tweak_key())
+            .ok_or_else(|| WalletError::InvalidPsbt("input is missing tweak".to_string()))?;
+
+        let mut signatures = 0;
+        for (peer, peer_key) in peer_keys {
+            let tweaked_peer_key: bitcoin::PublicKey = peer_key.tweak(tweak, secp).into();
+            if input.partial_sigs.contains_key(&tweaked_peer_key) {
+                signatures += 1;
+            } else {
+                missing.insert(*peer);
+            }
+        }
+
+        ready &= signatures >= peer_keys.threshold();
+    }
+
+    if ready {
+        return Ok(vec![]);
+    }
+
+    Ok(missing.into_iter().map(|peer| peer_keys[&peer]).collect())
 }
 
 #[instrument(level = "debug", skip_all)]
@@ -1100,6 +1522,7 @@ struct StatelessWallet<'a> {
     descriptor: &'a Descriptor<CompressedPublicKey>,
     secret_key: &'a secp256k1::SecretKey,
     secp: &'a secp256k1::Secp256k1<secp256k1::All>,
+    output_ordering: OutputOrdering,
 }
 
 impl<'a> StatelessWallet<'a> {
@@ -1163,6 +1586,12 @@ impl<'a> StatelessWallet<'a> {
     // * `remaining_utxos`: All other spendable UXTOs
     // * `fee_rate`: How much needs to be spent on fees
     // * `change_tweak`: How the federation can recognize it's change UTXO
+    // * `lock_time`: The nLockTime of the tx, peg-outs derive it from the
+    //   consensus block height according to the federation's `PegOutLockTime`
+    //   policy so all peers build the same tx. Since the inputs signal
+    //   RBF the lock time is enforced, so a replacement must not use a higher
+    //   lock time than the chain tip, and descriptors with timelocked spending
+    //   branches would need to take it into account.
     // * `rbf`: If this is an RBF transaction
     #[allow(clippy::too_many_arguments)]
     fn create_tx(
@@ -1173,6 +1602,7 @@ impl<'a> StatelessWallet<'a> {
         mut remaining_utxos: Vec<(UTXOKey, SpendableUTXO)>,
         mut fee_rate: Feerate,
         change_tweak: &[u8],
+        lock_time: PackedLockTime,
         rbf: Option<Rbf>,
     ) -> Result<UnsignedTransaction, WalletError> {
         // Add the rbf fees to the existing tx fees
@@ -1188,25 +1618,12 @@ impl<'a> StatelessWallet<'a> {
         // and the maximum weight per added input which we will add every time
         // we select an input.
         let change_script = self.derive_script(change_tweak);
-        let out_weight = (destination.len() * 4 + 1 + 32
-            // Add change script weight, it's very likely to be needed if not we just overpay in fees
-            + 1 // script len varint, 1 byte for all addresses we accept
-            + change_script.len() * 4 // script len
-            + 32) as u64; // value
-        let mut total_weight = 16 + // version
-            12 + // up to 2**16-1 inputs
-            12 + // up to 2**16-1 outputs
-            out_weight + // weight of all outputs
-            16; // lock time
-        let max_input_weight = (self
-            .descriptor
-            .max_satisfaction_weight()
-            .expect("is satisfyable") +
-            128 + // TxOutHash
-            16 + // TxOutIndex
-            16) as u64; // sequence
+        let (mut total_weight, max_input_weight) = self.tx_weights(&destination, &change_script);
 
-        // Ensure deterministic ordering of UTXOs for all peers
+        // Ensure deterministic ordering of UTXOs for all peers. Since we pop from the
+        // end this selects the largest UTXOs first, which keeps the number of inputs
+        // (and thus the number of signatures every peer has to produce) low, but
+        // leaves the small UTXOs for later peg-outs to spend.
         included_utxos.sort_by_key(|(_, utxo)| utxo.amount);
         remaining_utxos.sort_by_key(|(_, utxo)| utxo.amount);
         included_utxos.extend(remaining_utxos);
@@ -1231,20 +1648,41 @@ impl<'a> StatelessWallet<'a> {
         // We always pay ourselves change back to ensure that we don't lose anything due
         // to dust
         let change = total_selected_value - fees - peg_out_amount;
-        let output: Vec<TxOut> = vec![
-            TxOut {
-                value: peg_out_amount.to_sat(),
-                script_pubkey: destination.clone(),
-            },
-            TxOut {
-                value: change.to_sat(),
-                script_pubkey: change_script,
-            },
-        ];
         let mut change_out = bitcoin::util::psbt::Output::default();
         change_out
             .proprietary
             .insert(proprietary_tweak_key(), change_tweak.to_vec());
+        let mut outputs = vec![
+            (
+                TxOut {
+                    value: peg_out_amount.to_sat(),
+                    script_pubkey: destination.clone(),
+                },
+                bitcoin::util::psbt::Output::default(),
+            ),
+            (
+                TxOut {
+                    value: change.to_sat(),
+                    script_pubkey: change_script,
+                },
+                change_out,
+            ),
+        ];
+
+        // The change output is recognized by its script, not its position, so we are
+        // free to reorder as long as every peer ends up with the same order
+        match self.output_ordering {
+            OutputOrdering::PegOutFirst => {}
+            OutputOrdering::SeededShuffle => {
+                outputs.sort_by_cached_key(|(tx_out, _)| {
+                    let mut engine = sha256::Hash::engine();
+                    engine.input(change_tweak);
+                    engine.input(&bitcoin::consensus::encode::serialize(tx_out));
+                    sha256::Hash::from_engine(engine)
+                });
+            }
+        }
+        let (output, psbt_outputs): (Vec<TxOut>, Vec<_>) = outputs.into_iter().unzip();
 
         info!(
             inputs = selected_utxos.len(),
@@ -1258,7 +1696,7 @@ impl<'a> StatelessWallet<'a> {
 
         let transaction = Transaction {
             version: 2,
-            lock_time: PackedLockTime::ZERO,
+            lock_time,
             input: selected_utxos
                 .iter()
                 .map(|(utxo_key, _utxo)| TxIn {
@@ -1322,7 +1760,7 @@ impl<'a> StatelessWallet<'a> {
                     }
                 })
                 .collect(),
-            outputs: vec![Default::default(), change_out],
+            outputs: psbt_outputs,
         };
 
         Ok(UnsignedTransaction {
@@ -1340,6 +1778,59 @@ impl<'a> StatelessWallet<'a> {
         })
     }
 
+    /// Returns the weight of a peg-out tx to `destination` without any inputs
+    /// and the maximum weight every input adds to it
+    fn tx_weights(&self, destination: &Script, change_script: &Script) -> (u64, u64) {
+        let out_weight = (destination.len() * 4 + 1 + 32
+            // Add change script weight, it's very likely to be needed if not we just overpay in fees
+            + 1 // script len varint, 1 byte for all addresses we accept
+            + change_script.len() * 4 // script len
+            + 32) as u64; // value
+        let base_weight = 16 + // version
+            12 + // up to 2**16-1 inputs
+            12 + // up to 2**16-1 outputs
+            out_weight + // weight of all outputs
+            16; // lock time
+        let max_input_weight = (self
+            .descriptor
+            .max_satisfaction_weight()
+            .expect("is satisfyable") +
+            128 + // TxOutHash
+            16 + // TxOutIndex
+            16) as u64; // sequence
+
+        (base_weight, max_input_weight)
+    }
+
+    /// Largest amount [`Self::create_tx`] can send to `destination` by spending
+    /// all of `utxos`, after paying the fees at `fee_rate` and keeping a change
+    /// output at the dust limit. Zero if that isn't enough for a peg-out above
+    /// the dust limit.
+    fn max_peg_out_amount(
+        &self,
+        destination: &Script,
+        utxos: &[(UTXOKey, SpendableUTXO)],
+        fee_rate: Feerate,
+        change_tweak: &[u8],
+    ) -> bitcoin::Amount {
+        let change_script = self.derive_script(change_tweak);
+        let (base_weight, max_input_weight) = self.tx_weights(destination, &change_script);
+
+        let total_value = utxos
+            .iter()
+            .map(|(_, utxo)| utxo.amount)
+            .fold(bitcoin::Amount::ZERO, |total, amount| total + amount);
+        let fees = fee_rate.calculate_fee(base_weight + max_input_weight * utxos.len() as u64);
+
+        match total_value
+            .checked_sub(fees)
+            .and_then(|value| value.checked_sub(change_script.dust_value()))
+        {
+            Some(amount) if amount >= destination.dust_value() => amount,
+            _ => bitcoin::Amount::ZERO,
+        }
+    }
+
     fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) {
         let mut tx_hasher = SighashCache::new(&psbt.unsigned_tx);
 
@@ -1388,6 +1879,28 @@ impl<'a> StatelessWallet<'a> {
         }
     }
 
+    /// The outputs of `tx` that pay back to the federation using `tweak`
+    fn change_utxos(&self, tx: &Transaction, tweak: [u8; 32]) -> Vec<(UTXOKey, SpendableUTXO)> {
+        let script_pk = self.derive_script(&tweak);
+        tx.output
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| output.script_pubkey == script_pk)
+            .map(|(idx, output)| {
+                (
+                    UTXOKey(bitcoin::OutPoint {
+                        txid: tx.txid(),
+                        vout: idx as u32,
+                    }),
+                    SpendableUTXO {
+                        tweak,
+                        amount: bitcoin::Amount::from_sat(output.value),
+                    },
+                )
+            })
+            .collect()
+    }
+
     fn derive_script(&self, tweak: &[u8]) -> Script {
         struct CompressedPublicKeyTranslator<'t, 's, Ctx: Verification> {
             tweak: &'t [u8],
@@ -1439,46 +1952,162 @@ impl<'a> StatelessWallet<'a> {
 #[cfg(test)]
 mod tests {
 
+    use std::collections::{BTreeMap, BTreeSet};
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use bitcoin::Network::{Bitcoin, Testnet};
-    use bitcoin::{Address, Amount, Network, OutPoint, Txid};
-    use fedimint_core::{BitcoinHash, Feerate};
-    use fedimint_wallet_common::{PegOut, PegOutFees, Rbf, WalletOutput};
+    use bitcoin::{Address, Amount, Network, OutPoint, PackedLockTime, Txid};
+    use fedimint_bitcoind::IBitcoindRpc;
+    use fedimint_core::bitcoinrpc::BitcoinRpcConfig;
+    use fedimint_core::db::mem_impl::MemDatabase;
+    use fedimint_core::db::Database;
+    use fedimint_core::module::registry::ModuleDecoderRegistry;
+    use fedimint_core::task::TaskGroup;
+    use fedimint_core::{apply, async_trait_maybe_send, BitcoinHash, Feerate, PeerId};
+    use fedimint_testing::btc::mock::FakeBitcoinTest;
+    use fedimint_testing::btc::BitcoinTest;
+    use fedimint_wallet_common::config::{
+        OutputOrdering, PegOutAddressType, PegOutLockTime, WalletConfig,
+    };
+    use fedimint_wallet_common::db::{PendingTransactionKey, UnsignedTransactionKey};
+    use fedimint_wallet_common::{PegOut, PegOutFees, PendingTransaction, Rbf, WalletOutput};
     use miniscript::descriptor::Wsh;
+    use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
 
     use crate::common::PegInDescriptor;
-    use crate::{CompressedPublicKey, OsRng, SpendableUTXO, StatelessWallet, UTXOKey, WalletError};
+    use crate::{
+        available_utxos, combine_pegout_psbts, decode_pegout_psbt_base64, fee_rate_spread,
+        is_outpoint_matured, missing_signatures, peg_in_descriptor_checksum, validate_address_type,
+        verify_pegout_psbt, CompressedPublicKey, FeeEstimator, OsRng, SpendableUTXO,
+        StatelessWallet, UTXOKey, UnsignedTransaction, UtxoStats, Wallet, WalletError,
+    };
 
-    #[test]
-    fn create_tx_should_validate_amounts() {
-        let secp = secp256k1::Secp256k1::new();
+    /// A 3-of-4 federation sharing a peg-in descriptor
+    struct TestFederation {
+        secp: Secp256k1<All>,
+        keys: Vec<(SecretKey, PublicKey)>,
+        descriptor: PegInDescriptor,
+    }
 
-        let descriptor = PegInDescriptor::Wsh(
-            Wsh::new_sortedmulti(
+    impl TestFederation {
+        fn new() -> Self {
+            let secp = Secp256k1::new();
+            let keys = (0..4)
+                .map(|_| secp.generate_keypair(&mut OsRng))
+                .collect::<Vec<_>>();
+            let descriptor = PegInDescriptor::Wsh(
+                Wsh::new_sortedmulti(
+                    3,
+                    keys.iter()
+                        .map(|(_, key)| CompressedPublicKey { key: *key })
+                        .collect(),
+                )
+                .unwrap(),
+            );
+
+            TestFederation {
+                secp,
+                keys,
+                descriptor,
+            }
+        }
+
+        fn peer_keys(&self) -> BTreeMap<PeerId, CompressedPublicKey> {
+            self.keys
+                .iter()
+                .enumerate()
+                .map(|(peer, (_, key))| {
+                    (PeerId::from(peer as u16), CompressedPublicKey { key: *key })
+                })
+                .collect()
+        }
+
+        fn wallet(&self, peer: usize) -> StatelessWallet<'_> {
+            self.wallet_with_ordering(peer, OutputOrdering::PegOutFirst)
+        }
+
+        fn wallet_with_ordering(
+            &self,
+            peer: usize,
+            output_ordering: OutputOrdering,
+        ) -> StatelessWallet<'_> {
+            StatelessWallet {
+                descriptor: &self.descriptor,
+                secret_key: &self.keys[peer].0,
+                secp: &self.secp,
+                output_ordering,
+            }
+        }
+
+        /// The first peer's wallet module, connected to `bitcoin`
+        async fn server_wallet(&self, bitcoin: &FakeBitcoinTest, db: Database) -> Wallet {
+            let rpc_cfg = BitcoinRpcConfig {
+                kind: "bitcoind".to_string(),
+                url: "http://ignored".parse().unwrap(),
+            };
+            let cfg = WalletConfig::new(
+                self.peer_keys(),
+                self.keys[0].0,
                 3,
-                (0..4)
-                    .map(|_| secp.generate_keypair(&mut OsRng))
-                    .map(|(_, key)| CompressedPublicKey { key })
-                    .collect(),
-            )
-            .unwrap(),
-        );
+                Network::Regtest,
+                10,
+                rpc_cfg.clone(),
+                rpc_cfg,
+                OutputOrdering::PegOutFirst,
+                None,
+                PegOutLockTime::Zero,
+            );
+            Wallet::new_with_bitcoind(cfg, db, bitcoin.clone().into(), &mut TaskGroup::new())
+                .await
+                .expect("wallet starts")
+        }
+    }
 
-        let (secret_key, _) = secp.generate_keypair(&mut OsRng);
+    /// One UTXO per amount, all sharing the same tweak
+    fn test_utxos(sats: &[u64]) -> Vec<(UTXOKey, SpendableUTXO)> {
+        sats.iter()
+            .enumerate()
+            .map(|(vout, sats)| {
+                (
+                    UTXOKey(OutPoint {
+                        txid: Txid::all_zeros(),
+                        vout: vout as u32,
+                    }),
+                    SpendableUTXO {
+                        tweak: [0; 32],
+                        amount: Amount::from_sat(*sats),
+                    },
+                )
+            })
+            .collect()
+    }
 
-        let wallet = StatelessWallet {
-            descriptor: &descriptor,
-            secret_key: &secret_key,
-            secp: &secp,
-        };
+    fn test_recipient() -> Address {
+        Address::from_str("32iVBEu4dxkUQk9dJbZUiBiQdmypcEyJRf").unwrap()
+    }
 
-        let spendable = SpendableUTXO {
-            tweak: [0; 32],
-            amount: Amount::from_sat(3000),
-        };
+    /// Pegs out 1000 sats to [`test_recipient`] from a single 3000 sat UTXO
+    fn test_tx(wallet: &StatelessWallet<'_>) -> UnsignedTransaction {
+        wallet
+            .create_tx(
+                Amount::from_sat(1000),
+                test_recipient().script_pubkey(),
+                vec![],
+                test_utxos(&[3000]),
+                Feerate { sats_per_kvb: 1000 },
+                &[],
+                PackedLockTime::ZERO,
+                None,
+            )
+            .expect("is ok")
+    }
 
-        let recipient = Address::from_str("32iVBEu4dxkUQk9dJbZUiBiQdmypcEyJRf").unwrap();
+    #[test]
+    fn create_tx_should_validate_amounts() {
+        let federation = TestFederation::new();
+        let wallet = federation.wallet(0);
+        let recipient = test_recipient();
 
         let fee = Feerate { sats_per_kvb: 1000 };
         let weight = 875;
@@ -1488,25 +2117,16 @@ mod tests {
             Amount::from_sat(2000),
             recipient.script_pubkey(),
             vec![],
-            vec![(UTXOKey(OutPoint::null()), spendable.clone())],
+            test_utxos(&[3000]),
             fee,
             &[],
+            PackedLockTime::ZERO,
             None,
         );
         assert_eq!(tx, Err(WalletError::NotEnoughSpendableUTXO));
 
         // successful tx creation
-        let mut tx = wallet
-            .create_tx(
-                Amount::from_sat(1000),
-                recipient.script_pubkey(),
-                vec![],
-                vec![(UTXOKey(OutPoint::null()), spendable)],
-                fee,
-                &[],
-                None,
-            )
-            .expect("is ok");
+        let mut tx = test_tx(&wallet);
 
         // peg out weight is incorrectly set to 0
         let res = wallet.validate_tx(&tx, &rbf(fee.sats_per_kvb, 0), fee, Network::Bitcoin);
@@ -1546,6 +2166,528 @@ mod tests {
         assert_eq!(res, Err(WalletError::WrongNetwork(Testnet, Bitcoin)));
     }
 
+    #[test]
+    fn create_tx_should_select_largest_utxos_first() {
+        let federation = TestFederation::new();
+
+        // the smaller UTXOs would suffice as well, but need more inputs
+        let tx = federation
+            .wallet(0)
+            .create_tx(
+                Amount::from_sat(5000),
+                test_recipient().script_pubkey(),
+                vec![],
+                test_utxos(&[1000, 2000, 3000, 10_000]),
+                Feerate { sats_per_kvb: 1000 },
+                &[],
+                PackedLockTime::ZERO,
+                None,
+            )
+            .expect("is ok");
+
+        assert_eq!(tx.selected_utxos.len(), 1);
+        assert_eq!(tx.selected_utxos[0].1.amount, Amount::from_sat(10_000));
+    }
+
+    #[test]
+    fn pegout_psbt_base64_should_round_trip() {
+        let federation = TestFederation::new();
+        let tx = test_tx(&federation.wallet(0));
+
+        let b64 = base64::encode(bitcoin::consensus::encode::serialize(&tx.psbt));
+        assert_eq!(decode_pegout_psbt_base64(&b64), Ok(tx.psbt.clone()));
+
+        // not base64 at all
+        assert!(decode_pegout_psbt_base64("not a psbt!").is_err());
+
+        // valid base64 but missing the PSBT magic
+        assert!(decode_pegout_psbt_base64(&base64::encode(b"hello world")).is_err());
+
+        // inputs must carry the tweak so we can sign them
+        let mut psbt = tx.psbt;
+        psbt.inputs[0].proprietary.clear();
+        let b64 = base64::encode(bitcoin::consensus::encode::serialize(&psbt));
+        assert!(decode_pegout_psbt_base64(&b64).is_err());
+    }
+
+    #[test]
+    fn verify_pegout_psbt_should_reject_tampering() {
+        let federation = TestFederation::new();
+        let approved = test_tx(&federation.wallet(0)).psbt;
+
+        // other peers' signatures don't change what is being signed
+        let mut signed = approved.clone();
+        federation.wallet(1).sign_psbt(&mut signed);
+        assert_eq!(verify_pegout_psbt(&signed, &approved), Ok(()));
+
+        let attacker = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap();
+        let mut tampered = approved.clone();
+        tampered.unsigned_tx.output[0].script_pubkey = attacker.script_pubkey();
+        assert!(verify_pegout_psbt(&tampered, &approved).is_err());
+
+        let mut tampered = approved.clone();
+        tampered.inputs[0]
+            .witness_utxo
+            .as_mut()
+            .expect("is set")
+            .value += 1;
+        assert!(verify_pegout_psbt(&tampered, &approved).is_err());
+    }
+
+    #[tokio::test]
+    async fn sync_should_only_recognize_change_after_confirmation() {
+        let federation = TestFederation::new();
+        let bitcoin = FakeBitcoinTest::new();
+        bitcoin.mine_blocks(10).await;
+        let db = Database::new(MemDatabase::new(), ModuleDecoderRegistry::default());
+        let wallet = federation.server_wallet(&bitcoin, db.clone()).await;
+
+        let change_tweak = [1; 32];
+        let unsigned = wallet
+            .offline_wallet()
+            .create_tx(
+                Amount::from_sat(1000),
+                test_recipient().script_pubkey(),
+                vec![],
+                test_utxos(&[3000]),
+                Feerate { sats_per_kvb: 1000 },
+                &change_tweak,
+                PackedLockTime::ZERO,
+                None,
+            )
+            .expect("is ok");
+        let pending = PendingTransaction {
+            tx: unsigned.psbt.unsigned_tx.clone(),
+            tweak: change_tweak,
+            change: unsigned.change,
+            destination: unsigned.destination,
+            fees: unsigned.fees,
+            selected_utxos: unsigned.selected_utxos,
+            peg_out_amount: unsigned.peg_out_amount,
+            rbf: unsigned.rbf,
+        };
+        let txid = pending.tx.txid();
+        let change = wallet
+            .offline_wallet()
+            .change_utxos(&pending.tx, change_tweak);
+        assert_eq!(change.len(), 1);
+        let (change_key, change_utxo) = change[0].clone();
+
+        let mut dbtx = db.begin_transaction().await;
+        let mut dbtx = dbtx.get_isolated();
+        dbtx.insert_entry(&PendingTransactionKey(txid), &pending)
+            .await;
+
+        // the peg-out was only broadcast, so its change must not be spendable yet
+        bitcoin.submit_transaction(pending.tx.clone()).await;
+        wallet.sync_up_to_consensus_height(&mut dbtx, 0, 9).await;
+        assert_eq!(dbtx.get_value(&change_key).await, None);
+        assert!(available_utxos(&mut dbtx).await.is_empty());
+        assert!(dbtx.get_value(&PendingTransactionKey(txid)).await.is_some());
+
+        // once the peg-out is mined its change becomes a regular UTXO
+        bitcoin.mine_blocks(1).await;
+        wallet.sync_up_to_consensus_height(&mut dbtx, 9, 11).await;
+        assert_eq!(
+            available_utxos(&mut dbtx).await,
+            vec![(change_key, change_utxo)]
+        );
+        assert!(dbtx.get_value(&PendingTransactionKey(txid)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn is_outpoint_matured_should_tell_apart_matured_pending_and_unknown() {
+        let federation = TestFederation::new();
+        let wallet = federation.wallet(0);
+        let change_tweak = [1; 32];
+        let unsigned = wallet
+            .create_tx(
+                Amount::from_sat(1000),
+                test_recipient().script_pubkey(),
+                vec![],
+                test_utxos(&[3000]),
+                Feerate { sats_per_kvb: 1000 },
+                &change_tweak,
+                PackedLockTime::ZERO,
+                None,
+            )
+            .expect("is ok");
+        let tx = unsigned.psbt.unsigned_tx.clone();
+        let (change, _) = wallet.change_utxos(&tx, change_tweak).remove(0);
+        let peg_out = OutPoint {
+            txid: tx.txid(),
+            vout: 1 - change.0.vout,
+        };
+
+        let db = Database::new(MemDatabase::new(), ModuleDecoderRegistry::default());
+        let mut dbtx = db.begin_transaction().await;
+        let mut dbtx = dbtx.get_isolated();
+
+        assert_eq!(
+            is_outpoint_matured(&mut dbtx, &wallet, change.0).await,
+            Err(WalletError::UnknownOutPoint(change.0))
+        );
+
+        // change of a peg-out still collecting signatures
+        dbtx.insert_entry(&UnsignedTransactionKey(tx.txid()), &unsigned)
+            .await;
+        assert_eq!(
+            is_outpoint_matured(&mut dbtx, &wallet, change.0).await,
+            Ok(false)
+        );
+        assert_eq!(
+            is_outpoint_matured(&mut dbtx, &wallet, peg_out).await,
+            Err(WalletError::UnknownOutPoint(peg_out))
+        );
+        dbtx.remove_entry(&UnsignedTransactionKey(tx.txid())).await;
+
+        // change of a peg-out waiting for confirmation
+        let pending_tx = PendingTransaction {
+            tx: tx.clone(),
+            tweak: change_tweak,
+            change: unsigned.change,
+            destination: unsigned.destination,
+            fees: unsigned.fees,
+            selected_utxos: unsigned.selected_utxos,
+            peg_out_amount: unsigned.peg_out_amount,
+            rbf: unsigned.rbf,
+        };
+        dbtx.insert_entry(&PendingTransactionKey(tx.txid()), &pending_tx)
+            .await;
+        assert_eq!(
+            is_outpoint_matured(&mut dbtx, &wallet, change.0).await,
+            Ok(false)
+        );
+        assert_eq!(
+            is_outpoint_matured(&mut dbtx, &wallet, peg_out).await,
+            Err(WalletError::UnknownOutPoint(peg_out))
+        );
+
+        let (key, utxo) = test_utxos(&[3000]).remove(0);
+        dbtx.insert_entry(&key, &utxo).await;
+        assert_eq!(
+            is_outpoint_matured(&mut dbtx, &wallet, key.0).await,
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn create_tx_should_be_deterministic() {
+        let federation = TestFederation::new();
+        let utxos = test_utxos(&[1000, 2000, 3000, 10_000]);
+        let recipient = test_recipient();
+        let peg_out_amount = Amount::from_sat(12_000);
+
+        // Every peer uses its own key and may see the UTXOs in a different order
+        let build_tx = |peer: usize, utxos: Vec<(UTXOKey, SpendableUTXO)>| {
+            federation
+                .wallet(peer)
+                .create_tx(
+                    peg_out_amount,
+                    recipient.script_pubkey(),
+                    vec![],
+                    utxos,
+                    Feerate { sats_per_kvb: 1000 },
+                    &[1; 32],
+                    PackedLockTime(800_000),
+                    None,
+                )
+                .expect("is ok")
+        };
+
+        let tx_a = build_tx(0, utxos.clone());
+        let tx_b = build_tx(1, utxos.into_iter().rev().collect());
+        assert_eq!(tx_a.psbt.unsigned_tx.txid(), tx_b.psbt.unsigned_tx.txid());
+
+        // the recipient receives exactly the peg-out amount, fees come out of the change
+        let sent = tx_a
+            .psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .filter(|tx_out| tx_out.script_pubkey == recipient.script_pubkey())
+            .map(|tx_out| tx_out.value)
+            .sum::<u64>();
+        assert_eq!(sent, peg_out_amount.to_sat());
+        assert_eq!(tx_a.peg_out_amount, peg_out_amount);
+    }
+
+    #[test]
+    fn combine_pegout_psbts_should_merge_signatures() {
+        let federation = TestFederation::new();
+        let tx = test_tx(&federation.wallet(0));
+
+        let signed = (0..3)
+            .map(|peer| {
+                let mut psbt = tx.psbt.clone();
+                federation.wallet(peer).sign_psbt(&mut psbt);
+                psbt
+            })
+            .collect::<Vec<_>>();
+
+        let combined = combine_pegout_psbts(signed).expect("is ok");
+        assert_eq!(combined.inputs[0].partial_sigs.len(), 3);
+
+        // PSBTs for a different tx can't be combined
+        let mut other = tx.psbt.clone();
+        other.unsigned_tx.lock_time = PackedLockTime(1);
+        assert!(combine_pegout_psbts(vec![tx.psbt, other]).is_err());
+
+        assert!(combine_pegout_psbts(vec![]).is_err());
+    }
+
+    #[test]
+    fn missing_signatures_should_list_peers_yet_to_sign() {
+        let federation = TestFederation::new();
+        let peer_keys = federation.peer_keys();
+        let secp = &federation.secp;
+        let mut psbt = test_tx(&federation.wallet(0)).psbt;
+
+        assert_eq!(
+            missing_signatures(&psbt, &peer_keys, secp),
+            Ok(peer_keys.values().copied().collect())
+        );
+
+        federation.wallet(0).sign_psbt(&mut psbt);
+        federation.wallet(2).sign_psbt(&mut psbt);
+        assert_eq!(
+            missing_signatures(&psbt, &peer_keys, secp),
+            Ok(vec![
+                peer_keys[&PeerId::from(1)],
+                peer_keys[&PeerId::from(3)]
+            ])
+        );
+
+        // a threshold of signatures is enough to finalize
+        federation.wallet(3).sign_psbt(&mut psbt);
+        assert_eq!(missing_signatures(&psbt, &peer_keys, secp), Ok(vec![]));
+    }
+
+    #[test]
+    fn create_tx_should_pin_lock_time() {
+        let federation = TestFederation::new();
+        let consensus_height = 800_000;
+
+        let create_tx = |lock_time: PegOutLockTime| {
+            federation
+                .wallet(0)
+                .create_tx(
+                    Amount::from_sat(1000),
+                    test_recipient().script_pubkey(),
+                    vec![],
+                    test_utxos(&[3000]),
+                    Feerate { sats_per_kvb: 1000 },
+                    &[],
+                    lock_time.at_height(consensus_height),
+                    None,
+                )
+                .expect("is ok")
+        };
+
+        let tx = create_tx(PegOutLockTime::default());
+        assert_eq!(
+            tx.psbt.unsigned_tx.lock_time,
+            PackedLockTime(consensus_height)
+        );
+
+        let tx = create_tx(PegOutLockTime::Zero);
+        assert_eq!(tx.psbt.unsigned_tx.lock_time, PackedLockTime::ZERO);
+    }
+
+    #[test]
+    fn max_peg_out_amount_should_sweep_all_utxos() {
+        let federation = TestFederation::new();
+        let wallet = federation.wallet(0);
+        let utxos = test_utxos(&[10_000, 10_000, 10_000]);
+        let recipient = test_recipient();
+        let fee_rate = Feerate { sats_per_kvb: 1000 };
+
+        let max = wallet.max_peg_out_amount(&recipient.script_pubkey(), &utxos, fee_rate, &[0; 32]);
+        assert!(max > Amount::ZERO && max < Amount::from_sat(30_000));
+
+        let create_tx = |amount| {
+            wallet.create_tx(
+                amount,
+                recipient.script_pubkey(),
+                vec![],
+                utxos.clone(),
+                fee_rate,
+                &[0; 32],
+                PackedLockTime::ZERO,
+                None,
+            )
+        };
+        assert!(create_tx(max).is_ok());
+        assert_eq!(
+            create_tx(max + Amount::from_sat(1)).map(|_| ()),
+            Err(WalletError::NotEnoughSpendableUTXO)
+        );
+
+        // a fee rate this high eats all funds
+        let max = wallet.max_peg_out_amount(
+            &recipient.script_pubkey(),
+            &utxos,
+            Feerate {
+                sats_per_kvb: 1_000_000,
+            },
+            &[0; 32],
+        );
+        assert_eq!(max, Amount::ZERO);
+    }
+
+    #[test]
+    fn seeded_shuffle_should_be_deterministic() {
+        let federation = TestFederation::new();
+        let recipient = test_recipient();
+
+        // Every peer signs with its own key but has to build the same tx
+        let build_tx = |peer: usize, seed: &[u8]| {
+            federation
+                .wallet_with_ordering(peer, OutputOrdering::SeededShuffle)
+                .create_tx(
+                    Amount::from_sat(1000),
+                    recipient.script_pubkey(),
+                    vec![],
+                    test_utxos(&[3000]),
+                    Feerate { sats_per_kvb: 1000 },
+                    seed,
+                    PackedLockTime::ZERO,
+                    None,
+                )
+                .expect("is ok")
+        };
+
+        for seed in 0..8u8 {
+            let tx_a = build_tx(0, &[seed; 32]);
+            let tx_b = build_tx(1, &[seed; 32]);
+            assert_eq!(tx_a.psbt, tx_b.psbt);
+
+            // the change tweak has to travel with the change output
+            let change_script = tx_a
+                .psbt
+                .unsigned_tx
+                .output
+                .iter()
+                .zip(tx_a.psbt.outputs.iter())
+                .find(|(_, psbt_out)| !psbt_out.proprietary.is_empty())
+                .map(|(tx_out, _)| tx_out.script_pubkey.clone());
+            assert_ne!(change_script, Some(recipient.script_pubkey()));
+        }
+    }
+
+    #[test]
+    fn utxo_stats_should_describe_fragmentation() {
+        let stats = UtxoStats::from_amounts([3000, 1000, 10_000, 2000].map(Amount::from_sat));
+        assert_eq!(
+            stats,
+            UtxoStats {
+                count: 4,
+                total: Amount::from_sat(16_000),
+                smallest: Amount::from_sat(1000),
+                largest: Amount::from_sat(10_000),
+                median_value: Amount::from_sat(2500),
+            }
+        );
+
+        let stats = UtxoStats::from_amounts([3000, 1000, 2000].map(Amount::from_sat));
+        assert_eq!(stats.median_value, Amount::from_sat(2000));
+
+        let stats = UtxoStats::from_amounts([]);
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.smallest, Amount::ZERO);
+        assert_eq!(stats.median_value, Amount::ZERO);
+    }
+
+    #[test]
+    fn validate_address_type_should_enforce_allowlist() {
+        let p2sh = Address::from_str("32iVBEu4dxkUQk9dJbZUiBiQdmypcEyJRf").unwrap();
+        let p2wpkh = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap();
+        let allowed = BTreeSet::from([PegOutAddressType::P2wpkh, PegOutAddressType::P2tr]);
+
+        // everything is allowed without an allowlist
+        assert_eq!(validate_address_type(&p2sh, None), Ok(()));
+
+        assert_eq!(validate_address_type(&p2wpkh, Some(&allowed)), Ok(()));
+        assert_eq!(
+            validate_address_type(&p2sh, Some(&allowed)),
+            Err(WalletError::DisallowedAddressType(p2sh.clone()))
+        );
+    }
+
+    #[test]
+    fn peg_in_descriptor_checksum_matches_bip380() {
+        let descriptor = PegInDescriptor::Wsh(
+            Wsh::new_sortedmulti(
+                1,
+                [
+                    "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                    "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+                ]
+                .into_iter()
+                .map(|key| CompressedPublicKey::from_str(key).unwrap())
+                .collect(),
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(peg_in_descriptor_checksum(&descriptor), "r0ztsdge");
+    }
+
+    #[test]
+    fn fee_rate_spread_is_difference_of_extreme_votes() {
+        let votes = [3000, 1000, 25000, 2000].map(|sats_per_kvb| Feerate { sats_per_kvb });
+        assert_eq!(
+            fee_rate_spread(&votes),
+            Some(Feerate {
+                sats_per_kvb: 24000
+            })
+        );
+
+        assert_eq!(
+            fee_rate_spread(&votes[..1]),
+            Some(Feerate { sats_per_kvb: 0 })
+        );
+        assert_eq!(fee_rate_spread(&[]), None);
+    }
+
+    /// Always estimates the same fee rate
+    #[derive(Debug)]
+    struct FixedFeeEstimator(Option<Feerate>);
+
+    #[apply(async_trait_maybe_send!)]
+    impl FeeEstimator for FixedFeeEstimator {
+        async fn estimate(&self, _confirmation_target: u16) -> anyhow::Result<Option<Feerate>> {
+            Ok(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn fee_rate_should_come_from_the_fee_estimator() {
+        let federation = TestFederation::new();
+        let bitcoin = FakeBitcoinTest::new();
+        let db = Database::new(MemDatabase::new(), ModuleDecoderRegistry::default());
+        let wallet = federation.server_wallet(&bitcoin, db).await;
+
+        // the fake bitcoind has no estimate, so the default fee is used
+        assert_eq!(wallet.fee_rate().await, Feerate { sats_per_kvb: 1000 });
+        assert_eq!(wallet.call_stats().get_fee_rate, 1);
+
+        let fee_rate = Feerate { sats_per_kvb: 5000 };
+        let wallet = wallet.with_fee_estimator(Arc::new(FixedFeeEstimator(Some(fee_rate))));
+        assert_eq!(wallet.fee_rate().await, fee_rate);
+        assert_eq!(wallet.call_stats().get_fee_rate, 1);
+    }
+
+    #[test]
+    fn pegout_fees_keep_sub_sat_precision() {
+        let fees = PegOutFees::new(1500, 875);
+        assert_eq!(fees.amount(), Amount::from_sat(1312));
+        assert_eq!(
+            fees.amount_msats(),
+            fedimint_core::Amount::from_msats(1_312_500)
+        );
+    }
+
     fn rbf(sats_per_kvb: u64, total_weight: u64) -> WalletOutput {
         WalletOutput::Rbf(Rbf {
             fees: PegOutFees::new(sats_per_kvb, total_weight),
@@ -1763,7 +2905,7 @@ mod fedimint_migration_tests {
         validate_migrations(
             "wallet",
             |db| async move {
-                let module = DynServerModuleGen::from(WalletGen);
+                let module = DynServerModuleGen::from(WalletGen::default());
                 apply_migrations(
                     &db,
                     module.module_kind().to_string(),
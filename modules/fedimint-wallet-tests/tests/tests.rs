@@ -14,7 +14,7 @@ fn fixtures() -> Fixtures {
     let fixtures = Fixtures::new_primary(DummyClientGen, DummyGen, DummyGenParams::default());
     let wallet_params = WalletGenParams::regtest(fixtures.bitcoin_server());
     let wallet_client = WalletClientGen::new(fixtures.bitcoin_client());
-    fixtures.with_module(wallet_client, WalletGen, wallet_params)
+    fixtures.with_module(wallet_client, WalletGen::default(), wallet_params)
 }
 
 fn bsats(satoshi: u64) -> bitcoin::Amount {